@@ -1,9 +1,10 @@
 use std::fmt::format;
 
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, SledKvsEngine, SyncPolicy};
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use sled::open;
+use std::thread;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
@@ -39,6 +40,43 @@ fn set_bench(c: &mut Criterion) {
     group.finish();
 }
 
+fn concurrent_set_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_set_bench");
+    for policy in &[SyncPolicy::Always, SyncPolicy::GroupCommit] {
+        group.bench_function(format!("kvs_{:?}", policy), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let options = KvStoreOptions::default().sync_policy(*policy);
+                    (
+                        KvStore::open_with(temp_dir.path(), options).unwrap(),
+                        temp_dir,
+                    )
+                },
+                |(store, _temp_dir)| {
+                    let handles: Vec<_> = (0..8)
+                        .map(|thread_id| {
+                            let store = store.clone();
+                            thread::spawn(move || {
+                                for i in 0..64 {
+                                    store
+                                        .set(format!("key{}_{}", thread_id, i), "value".to_string())
+                                        .unwrap();
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
     for i in &vec![8, 12, 16, 20] {
@@ -74,5 +112,5 @@ fn get_bench(c: &mut Criterion) {
     }
 }
 
-criterion_group!(benches, set_bench);
+criterion_group!(benches, set_bench, concurrent_set_bench);
 criterion_main!(benches);
@@ -8,10 +8,13 @@ use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+/// how often the sled engine removes expired keys, `KvStore` has its own default
+const TTL_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
@@ -31,6 +34,12 @@ struct Opt {
         possible_values = &Engine::variants(),
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Sets how writes are made durable: never, always, group or an interval such as 100ms",
+        value_name = "POLICY"
+    )]
+    sync: Option<SyncPolicy>,
 }
 
 arg_enum! {
@@ -71,10 +80,21 @@ fn run(opt: Opt) -> Result<()> {
     fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
     let pool = SharedQueueThreadPool::new(num_cpus::get() as u64)?;
 
-    match engine {
-        Engine::kvs => run_with_engine(KvStore::open(current_dir()?)?, pool, opt.addr),
-        Engine::sled => run_with_engine(
-            SledKvsEngine::new(sled::open(current_dir()?)?),
+    match (engine, opt.sync) {
+        (Engine::kvs, None) => run_with_engine(KvStore::open(current_dir()?)?, pool, opt.addr),
+        (Engine::kvs, Some(sync)) => run_with_engine(
+            KvStore::open_with(current_dir()?, KvStoreOptions::default().sync_policy(sync))?,
+            pool,
+            opt.addr,
+        ),
+        (Engine::sled, None) => run_with_engine(
+            SledKvsEngine::new(sled::open(current_dir()?)?).with_ttl_sweeper(TTL_SWEEP_INTERVAL)?,
+            pool,
+            opt.addr,
+        ),
+        (Engine::sled, Some(sync)) => run_with_engine(
+            SledKvsEngine::with_sync_policy(sled::open(current_dir()?)?, sync)?
+                .with_ttl_sweeper(TTL_SWEEP_INTERVAL)?,
             pool,
             opt.addr,
         ),
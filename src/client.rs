@@ -1,12 +1,15 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::common::{
+    BatchResponse, CasResponse, GetResponse, RemoveResponse, Request, SetResponse, TtlResponse,
+};
 use std::io::{BufReader, Write};
 use std::net::TcpStream;
+use std::time::Duration;
 use std::{io::BufWriter, net::ToSocketAddrs};
 
 use serde::Deserialize;
 use serde_json::de::{Deserializer, IoRead};
 
-use crate::{KvsError, Result};
+use crate::{KvsError, Result, WriteBatch};
 
 /// Key value store client.
 pub struct KvsClient {
@@ -26,7 +29,7 @@ impl KvsClient {
     }
 
     /// get the value of a given key from the server
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
         self.writer.flush()?;
         let resp = GetResponse::deserialize(&mut self.reader)?;
@@ -36,8 +39,8 @@ impl KvsClient {
         }
     }
 
-    /// set the value of a string key in the server
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+    /// set the value of a key in the server
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
         serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
         self.writer.flush()?;
         let resp = SetResponse::deserialize(&mut self.reader)?;
@@ -47,8 +50,35 @@ impl KvsClient {
         }
     }
 
-    /// remove a string key in the server
-    pub fn remove(&mut self, key: String) -> Result<()> {
+    /// set the value of a key in the server that expires once `ttl` has passed
+    pub fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &Request::SetWithTtl { key, value, ttl })?;
+        self.writer.flush()?;
+        let resp = SetResponse::deserialize(&mut self.reader)?;
+        match resp {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// get the lifetime a key in the server has left, `None` if it never expires
+    pub fn ttl_bytes(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        serde_json::to_writer(&mut self.writer, &Request::Ttl { key })?;
+        self.writer.flush()?;
+        let resp = TtlResponse::deserialize(&mut self.reader)?;
+        match resp {
+            TtlResponse::Ok(ttl) => Ok(ttl),
+            TtlResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// remove a key in the server
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
         serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
         self.writer.flush()?;
         let resp = RemoveResponse::deserialize(&mut self.reader)?;
@@ -57,4 +87,100 @@ impl KvsClient {
             RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
         }
     }
+
+    /// apply every write of `batch` in the server at once
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &Request::Batch { batch })?;
+        self.writer.flush()?;
+        let resp = BatchResponse::deserialize(&mut self.reader)?;
+        match resp {
+            BatchResponse::Ok(_) => Ok(()),
+            BatchResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// set a key in the server to `new` only if it holds `expected`
+    ///
+    /// Fails with `KvsError::CompareAndSwap` holding the current value otherwise.
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self.conditional_write(&Request::CompareAndSwap { key, expected, new })
+    }
+
+    /// set a key in the server only if it does not exist yet
+    pub fn set_if_absent_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.conditional_write(&Request::SetIfAbsent { key, value })
+    }
+
+    /// overwrite the value of a key in the server only if it exists
+    pub fn set_if_present_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.conditional_write(&Request::SetIfPresent { key, value })
+    }
+
+    fn conditional_write(&mut self, req: &Request) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, req)?;
+        self.writer.flush()?;
+        let resp = CasResponse::deserialize(&mut self.reader)?;
+        match resp {
+            CasResponse::Ok(_) => Ok(()),
+            CasResponse::Mismatch(current) => Err(KvsError::CompareAndSwap { current }),
+            CasResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// get the value of a string key from the server
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// set the value of a string key in the server
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// set the value of a string key in the server that expires once `ttl` has passed
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// remove a string key in the server
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// set a string key in the server to `new` only if it holds `expected`
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// set a string key in the server only if it does not exist yet
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<()> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// overwrite the value of a string key in the server only if it exists
+    pub fn set_if_present(&mut self, key: String, value: String) -> Result<()> {
+        self.set_if_present_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// get the lifetime a string key in the server has left, `None` if it never expires
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
 }
@@ -1,15 +1,50 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 
+use crate::WriteBatch;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    Ttl {
+        key: Vec<u8>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    SetIfAbsent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetIfPresent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
-    Ok(Option<String>),
+    Ok(Option<Vec<u8>>),
     Err(String),
 }
 
@@ -24,3 +59,23 @@ pub enum RemoveResponse {
     Ok(()),
     Err(String),
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TtlResponse {
+    Ok(Option<Duration>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BatchResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CasResponse {
+    Ok(()),
+    /// the condition failed, with the value the key holds
+    Mismatch(Option<Vec<u8>>),
+    Err(String),
+}
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+/// A group of writes that is applied all together or not at all
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result, WriteBatch};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("from".to_owned(), "90".to_owned());
+/// batch.set("to".to_owned(), "110".to_owned());
+/// batch.remove("pending".to_owned());
+/// store.write_batch(batch)?;
+/// # Ok(())
+/// # }
+/// # try_main().unwrap();
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    /// create an empty batch
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// add setting a key/value pair to the batch
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// add removing a key to the batch, a key that does not exist is left alone
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove { key });
+    }
+
+    /// add setting a string key/value pair to the batch
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    /// add removing a string key to the batch, a key that does not exist is left alone
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    /// the number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// whether the batch has no writes
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
@@ -0,0 +1,85 @@
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use log::error;
+
+use crate::{KvsError, Result};
+
+/// How hard an engine tries to make a write durable before acknowledging it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// hand the write to the operating system, a power failure can still lose it
+    Never,
+    /// sync the data to disk before every acknowledgement
+    Always,
+    /// sync in the background every given interval, a power failure can lose
+    /// the writes of the last interval
+    Interval(Duration),
+    /// like `Always`, but writes arriving while a sync is running share the next one
+    GroupCommit,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = KvsError;
+
+    /// parse `never`, `always`, `group` or an interval in milliseconds such as `100ms`
+    fn from_str(s: &str) -> Result<SyncPolicy> {
+        match s {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            "group" => Ok(SyncPolicy::GroupCommit),
+            _ => s
+                .strip_suffix("ms")
+                .and_then(|ms| ms.parse().ok())
+                .map(|ms| SyncPolicy::Interval(Duration::from_millis(ms)))
+                .ok_or_else(|| KvsError::StringError(format!("invalid sync policy: {}", s))),
+        }
+    }
+}
+
+/// A background thread running a function every interval, such as a sync.
+///
+/// Dropping it runs the function one last time and stops the thread, the function
+/// is told whether it runs for the last time.
+pub(crate) struct PeriodicTask {
+    shutdown: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeriodicTask {
+    pub(crate) fn spawn<F>(name: &str, interval: Duration, mut task: F) -> Result<PeriodicTask>
+    where
+        F: FnMut(bool) -> Result<()> + Send + 'static,
+    {
+        let (shutdown, rx) = channel::bounded::<()>(0);
+        let thread = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || loop {
+                let stop = !matches!(rx.recv_timeout(interval), Err(RecvTimeoutError::Timeout));
+                if let Err(e) = task(stop) {
+                    error!("Background task failed: {}", e);
+                }
+                if stop {
+                    break;
+                }
+            })?;
+        Ok(PeriodicTask {
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for PeriodicTask {
+    fn drop(&mut self) {
+        // disconnecting the channel wakes the thread up for its last run
+        self.shutdown.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("A background thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// milliseconds since the unix epoch
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// the expiry timestamp of a key written now that should live for `ttl`
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().min(u64::MAX as u128) as u64)
+}
+
+/// whether a key expiring at `expires_at` is gone by now
+pub(crate) fn is_expired(expires_at: u64) -> bool {
+    expires_at <= now_millis()
+}
+
+/// the lifetime a key expiring at `expires_at` has left
+pub(crate) fn remaining(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now_millis()))
+}
@@ -0,0 +1,106 @@
+//! Blob files holding values too large for the log.
+//!
+//! A value of at least `KvStoreOptions::blob_threshold` bytes is written as a set
+//! record of its own to the active blob file, which has the same format as a log
+//! file, and the log only gets a set record pointing to it. Compaction copies the
+//! pointer and leaves the value where it is, until enough of its blob file is
+//! garbage for the compaction to move the live values out and drop the file.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crossbeam_skiplist::SkipMap;
+use log::warn;
+
+use super::crypto::Cipher;
+use super::options::KvStoreOptions;
+use super::record::{decode_record, ends_in_file_header, read_file_header, Command};
+use super::{sorted_file_ids, CommandPos, KvStoreReader};
+use crate::{KvsError, Result};
+
+pub(super) fn blob_path(dir: &Path, file: u64) -> PathBuf {
+    dir.join(format!("{}.blob", file))
+}
+
+/// Where a value lives in a blob file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct BlobPos {
+    pub(super) file: u64,
+    pub(super) pos: u64,
+    pub(super) len: u64,
+}
+
+/// How much of a blob file is garbage
+#[derive(Clone, Copy, Debug)]
+pub(super) struct BlobStats {
+    pub(super) size: u64,
+    pub(super) garbage: u64,
+    /// key the values are sealed with
+    pub(super) key_id: Option<u32>,
+}
+
+impl BlobStats {
+    /// whether the live values should be moved out of the file
+    pub(super) fn should_collect(&self, options: &KvStoreOptions) -> bool {
+        self.garbage as f64 > options.blob_gc_ratio * self.size as f64
+    }
+}
+
+/// Measure the blob files in `dir`, every value `index` does not point to is garbage.
+pub(super) fn load_blob_stats(
+    dir: &Path,
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    options: &KvStoreOptions,
+) -> Result<BTreeMap<u64, BlobStats>> {
+    let mut live: BTreeMap<u64, u64> = BTreeMap::new();
+    for entry in index.iter() {
+        if let Some(blob) = entry.value().blob {
+            *live.entry(blob.file).or_default() += blob.len;
+        }
+    }
+
+    let mut blobs = BTreeMap::new();
+    for file in sorted_file_ids(dir, "blob")? {
+        let path = blob_path(dir, file);
+        // nothing can point into a blob file that a crash cut off right after creating it
+        if ends_in_file_header(&path)? {
+            if !options.read_only {
+                warn!("Removing {}.blob, it ends inside its file header", file);
+                fs::remove_file(&path)?;
+            }
+            continue;
+        }
+        let header = read_file_header(&mut File::open(&path)?, file, options)?;
+        let size = fs::metadata(&path)?.len();
+        blobs.insert(
+            file,
+            BlobStats {
+                size,
+                garbage: size - live.get(&file).copied().unwrap_or(0).min(size),
+                key_id: header.cipher.as_ref().map(Cipher::key_id),
+            },
+        );
+    }
+    Ok(blobs)
+}
+
+/// Read the set record of the value that the pointer at `cmd_pos` refers to.
+///
+/// A corrupted value is reported at the position of the pointer.
+pub(super) fn read_blob(reader: &KvStoreReader, cmd_pos: CommandPos) -> Result<Command> {
+    let blob = cmd_pos.blob.expect("the record is not a blob pointer");
+    reader.read_blob_and(blob, |record, cipher| {
+        decode_record(record, cipher).map_err(|e| match e.at(cmd_pos.gen, cmd_pos.pos) {
+            KvsError::Corruption { gen, pos, reason } => KvsError::Corruption {
+                gen,
+                pos,
+                reason: format!(
+                    "its value in {}.blob at offset {}: {}",
+                    blob.file, blob.pos, reason
+                ),
+            },
+            e => e,
+        })
+    })
+}
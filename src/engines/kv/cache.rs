@@ -0,0 +1,126 @@
+//! A size-bounded cache of the values read from the logs.
+//!
+//! Values are cached by the position of their record rather than by key. A write
+//! puts the new value at a new position, so a cached value can never be stale and
+//! the one it replaces is simply not looked up again until it is evicted. The
+//! same goes for snapshots, which look up older positions. Compaction drops the
+//! values of the generations it removed, whose positions are gone for good.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::CommandPos;
+
+/// Recently read values up to a number of bytes, evicting the least recently used
+pub(super) struct ValueCache {
+    capacity: usize,
+    state: Mutex<State>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+    /// value and last use of every cached record, by generation and position
+    entries: HashMap<(u64, u64), (Vec<u8>, u64)>,
+    /// cached records by their last use
+    recency: BTreeMap<u64, (u64, u64)>,
+    /// the next use
+    tick: u64,
+    /// bytes of all values together
+    size: usize,
+}
+
+impl State {
+    fn remove(&mut self, key: (u64, u64)) {
+        if let Some((value, used)) = self.entries.remove(&key) {
+            self.recency.remove(&used);
+            self.size -= value.len();
+        }
+    }
+}
+
+impl ValueCache {
+    /// a cache holding up to `capacity` bytes of values
+    pub(super) fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            state: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// the value of the record at `cmd_pos`, if cached
+    pub(super) fn get(&self, cmd_pos: &CommandPos) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let tick = state.tick;
+        let value = match state.entries.get_mut(&(cmd_pos.gen, cmd_pos.pos)) {
+            Some((value, used)) => {
+                let previous = std::mem::replace(used, tick);
+                Some((value.clone(), previous))
+            }
+            None => None,
+        };
+        match value {
+            Some((value, previous)) => {
+                state.recency.remove(&previous);
+                state.recency.insert(tick, (cmd_pos.gen, cmd_pos.pos));
+                state.tick += 1;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(value)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// cache `value` as the value of the record at `cmd_pos`
+    pub(super) fn insert(&self, cmd_pos: &CommandPos, value: Vec<u8>) {
+        if value.len() > self.capacity {
+            return;
+        }
+        let key = (cmd_pos.gen, cmd_pos.pos);
+        let mut state = self.state.lock().unwrap();
+        state.remove(key);
+        let tick = state.tick;
+        state.tick += 1;
+        state.size += value.len();
+        state.entries.insert(key, (value, tick));
+        state.recency.insert(tick, key);
+        while state.size > self.capacity {
+            let oldest = match state.recency.first_key_value() {
+                Some((_, &oldest)) => oldest,
+                None => break,
+            };
+            state.remove(oldest);
+        }
+    }
+
+    /// drop the values of generations a compaction removed
+    pub(super) fn remove_gens(&self, gens: &BTreeSet<u64>) {
+        let mut state = self.state.lock().unwrap();
+        let removed: Vec<(u64, u64)> = state
+            .entries
+            .keys()
+            .filter(|(gen, _)| gens.contains(gen))
+            .copied()
+            .collect();
+        for key in removed {
+            state.remove(key);
+        }
+    }
+
+    /// number of reads the cache served
+    pub(super) fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// number of reads that had to go to disk
+    pub(super) fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,584 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam_skiplist::SkipMap;
+use log::{debug, error};
+
+use super::blob::{blob_path, BlobPos, BlobStats};
+use super::crypto::Cipher;
+use super::hint::HintWriter;
+use super::options::KvStoreOptions;
+use super::record::{decode_record, read_file_header, read_record, Command, Record};
+use super::snapshot::Versions;
+use super::{
+    gen_files, log_path, open_log_writer, BufReaderWithPos, BufWriterWithPos, CommandPos, FileId,
+    KvStoreReader, KvStoreWriter,
+};
+use crate::Result;
+
+/// number of index entries remapped per hold of the writer lock
+const REMAP_CHUNK: usize = 1024;
+
+/// Counts the remaps of index entries a compaction did.
+///
+/// The index drops a key for a moment while its entry is replaced, so a reader
+/// that missed a key looks again if a remap may have hidden it.
+#[derive(Default)]
+pub(super) struct Remaps {
+    started: AtomicU64,
+    finished: AtomicU64,
+}
+
+impl Remaps {
+    fn start(&self) {
+        self.started.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn finish(&self) {
+        self.finished.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Run `read` on the index until `found` accepts what it returns or no remap
+    /// overlapped it.
+    pub(super) fn read<T>(&self, read: impl Fn() -> T, found: impl Fn(&T) -> bool) -> T {
+        loop {
+            let started = self.started.load(Ordering::SeqCst);
+            let idle = self.finished.load(Ordering::SeqCst) == started;
+            let result = read();
+            if found(&result) || (idle && self.started.load(Ordering::SeqCst) == started) {
+                return result;
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+pub(super) enum CompactionMsg {
+    Compact(CompactionJob),
+    Shutdown,
+}
+
+/// What a compaction merges and where it puts the result
+pub(super) struct CompactionJob {
+    /// generations whose live records are copied before they are removed
+    pub(super) inputs: BTreeSet<u64>,
+    /// generations reserved for the copies, above every input
+    pub(super) outputs: Range<u64>,
+    /// the oldest generation left alone that holds stale records, removes in newer
+    /// inputs are kept so those records stay removed
+    pub(super) oldest_stale: Option<u64>,
+    /// blob files whose live values are moved to the new blob file `blob_file`
+    pub(super) blobs: Vec<u64>,
+    pub(super) blob_file: u64,
+}
+
+/// How much of a generation is garbage
+#[derive(Clone, Copy, Debug)]
+pub(super) struct SegmentStats {
+    pub(super) size: u64,
+    pub(super) garbage: u64,
+    /// key the records are sealed with
+    pub(super) key_id: Option<u32>,
+}
+
+impl SegmentStats {
+    pub(super) fn new(size: u64, cipher: Option<&Cipher>) -> SegmentStats {
+        SegmentStats {
+            size,
+            garbage: 0,
+            key_id: cipher.map(Cipher::key_id),
+        }
+    }
+
+    /// whether the next compaction should take the generation on, which is always
+    /// the case unless the options ask to leave segments with little garbage alone
+    pub(super) fn should_compact(&self, options: &KvStoreOptions, cipher: Option<&Cipher>) -> bool {
+        match options.segment_garbage_ratio {
+            Some(ratio) => {
+                self.garbage as f64 > ratio * self.size as f64
+                    || self.key_id != cipher.map(Cipher::key_id)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Handle to the background compaction thread, shared by all clones of a `KvStore`.
+///
+/// Dropping it lets a running compaction finish and then stops the thread.
+pub(super) struct Compactor {
+    sender: Sender<CompactionMsg>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// create the channel used to schedule compactions
+    pub(super) fn channel() -> (Sender<CompactionMsg>, Receiver<CompactionMsg>) {
+        channel::unbounded()
+    }
+
+    pub(super) fn spawn(
+        sender: Sender<CompactionMsg>,
+        receiver: Receiver<CompactionMsg>,
+        writer: Arc<Mutex<KvStoreWriter>>,
+        index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+        reader: KvStoreReader,
+        versions: Arc<Versions>,
+        path: Arc<PathBuf>,
+    ) -> Result<Compactor> {
+        let thread = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || {
+                for msg in receiver {
+                    let job = match msg {
+                        CompactionMsg::Compact(job) => job,
+                        CompactionMsg::Shutdown => break,
+                    };
+                    let first_gen = job.outputs.start;
+                    let started = Instant::now();
+                    let result = compact(&writer, &index, &reader, &versions, &path, job);
+                    let mut writer = writer.lock().unwrap();
+                    writer.compacting = false;
+                    match result {
+                        Ok(()) => {
+                            writer.compactions += 1;
+                            writer.last_compaction = Some(started.elapsed());
+                        }
+                        Err(e) => error!("Compaction into {}.log failed: {}", first_gen, e),
+                    }
+                }
+            })?;
+        Ok(Compactor {
+            sender,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // the thread may already be gone if it panicked
+        let _ = self.sender.send(CompactionMsg::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The compaction thread panicked");
+            }
+        }
+    }
+}
+
+/// A compaction output generation that is still being written
+struct Segment {
+    gen: u64,
+    tmp_log: PathBuf,
+    writer: BufWriterWithPos<fs::File>,
+    hint_writer: HintWriter,
+}
+
+impl Segment {
+    fn create(path: &Path, gen: u64, options: &KvStoreOptions) -> Result<Segment> {
+        let tmp_log = log_path(path, gen).with_extension("log.tmp");
+        Ok(Segment {
+            gen,
+            writer: open_log_writer(&tmp_log, options)?,
+            tmp_log,
+            hint_writer: HintWriter::new(path, gen, options.cipher()),
+        })
+    }
+
+    /// make the segment durable and visible under its final name, returning its size
+    fn finish(mut self, path: &Path) -> Result<u64> {
+        self.writer.sync()?;
+        let size = self.writer.pos;
+        drop(self.writer);
+        fs::rename(&self.tmp_log, log_path(path, self.gen))?;
+        self.hint_writer.finish()?;
+        Ok(size)
+    }
+}
+
+/// The output generations of a compaction
+///
+/// A new generation is started whenever the current one passes the maximum segment
+/// size, the last reserved generation takes whatever does not fit. Unused
+/// generations are never created.
+struct Output {
+    segment: Segment,
+    /// reserved generations that are not started yet
+    gens: Range<u64>,
+    /// finished generations with their size
+    finished: Vec<(u64, u64)>,
+}
+
+impl Output {
+    fn create(path: &Path, mut gens: Range<u64>, options: &KvStoreOptions) -> Result<Output> {
+        let gen = gens.next().expect("empty compaction range");
+        Ok(Output {
+            segment: Segment::create(path, gen, options)?,
+            gens,
+            finished: Vec::new(),
+        })
+    }
+
+    /// Append `record` and return where it went.
+    ///
+    /// The values it points to in `blobs` are made durable before it can be.
+    fn append(
+        &mut self,
+        path: &Path,
+        record: &[u8],
+        blobs: &mut BlobCollection,
+        options: &KvStoreOptions,
+    ) -> Result<(u64, Range<u64>)> {
+        if options
+            .max_segment_size
+            .is_some_and(|max| self.segment.writer.pos >= max)
+            && !self.gens.is_empty()
+        {
+            let next = Segment::create(path, self.gens.next().unwrap(), options)?;
+            blobs.sync()?;
+            let full = std::mem::replace(&mut self.segment, next);
+            let gen = full.gen;
+            self.finished.push((gen, full.finish(path)?));
+        }
+        let start = self.segment.writer.pos;
+        self.segment.writer.write_all(record)?;
+        Ok((self.segment.gen, start..self.segment.writer.pos))
+    }
+
+    /// finish the last generation, returning every generation with its size
+    fn finish(mut self, path: &Path, blobs: &mut BlobCollection) -> Result<Vec<(u64, u64)>> {
+        blobs.sync()?;
+        let gen = self.segment.gen;
+        self.finished.push((gen, self.segment.finish(path)?));
+        Ok(self.finished)
+    }
+}
+
+/// Blob files a compaction collects, and the one their live values move to
+struct BlobCollection {
+    files: HashSet<u64>,
+    output: u64,
+    /// created with the first value that moves
+    writer: Option<BufWriterWithPos<fs::File>>,
+}
+
+impl BlobCollection {
+    fn new(files: Vec<u64>, output: u64) -> BlobCollection {
+        BlobCollection {
+            files: files.into_iter().collect(),
+            output,
+            writer: None,
+        }
+    }
+
+    /// Move the value at `blob`, which the pointer at `pos` refers to, re-encoding it
+    /// as `options` ask.
+    fn move_value(
+        &mut self,
+        reader: &KvStoreReader,
+        path: &Path,
+        pos: CommandPos,
+        blob: BlobPos,
+        cipher: Option<&Cipher>,
+        options: &KvStoreOptions,
+    ) -> Result<BlobPos> {
+        let (record, old_cipher) = reader.read_blob_and(blob, |record, cipher| {
+            Ok((record.to_vec(), cipher.cloned()))
+        })?;
+        let record = rewrite(record, pos, old_cipher.as_ref(), cipher, options)?;
+        if self.writer.is_none() {
+            self.writer = Some(open_log_writer(&blob_path(path, self.output), options)?);
+        }
+        let writer = self.writer.as_mut().unwrap();
+        let new_pos = writer.pos;
+        writer.write_all(&record)?;
+        Ok(BlobPos {
+            file: self.output,
+            pos: new_pos,
+            len: record.len() as u64,
+        })
+    }
+
+    /// make the moved values durable, which has to happen before any pointer to them is
+    fn sync(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.sync()?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy every live record of the input generations into the output generations,
+/// dropping expired keys. Records compressed or encrypted otherwise than the options
+/// ask are rewritten, which is how a new key replaces the old one.
+///
+/// The copying happens without holding the writer lock, writers keep appending to
+/// the newer active generation meanwhile. Remapping the index takes the lock for a
+/// bounded number of keys at a time.
+///
+/// Values in blob files are left where they are, only their pointers are copied,
+/// unless the blob file is collected. Pointers to a collected blob file are moved
+/// out of generations that are not inputs as well.
+///
+/// Removes are dropped along with the inputs, unless a generation left alone may
+/// still hold an older record of their key, which would come back otherwise.
+///
+/// Older versions that open snapshots see are not copied, the inputs and blob files
+/// holding them are retired instead and only removed once those snapshots close.
+fn compact(
+    writer: &Mutex<KvStoreWriter>,
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    reader: &KvStoreReader,
+    versions: &Versions,
+    path: &Path,
+    job: CompactionJob,
+) -> Result<()> {
+    let CompactionJob {
+        inputs,
+        outputs,
+        oldest_stale,
+        blobs,
+        blob_file,
+    } = job;
+    let first_output = outputs.start;
+    let options = &reader.options;
+    let cipher = options.cipher();
+    let mut blobs = BlobCollection::new(blobs, blob_file);
+    let mut output = Output::create(path, outputs, options)?;
+
+    let mut moved = Vec::new();
+    let mut expired = Vec::new();
+    for entry in index.iter() {
+        let old_pos = *entry.value();
+        let input = inputs.contains(&old_pos.gen);
+        let collected = old_pos.blob.filter(|blob| blobs.files.contains(&blob.file));
+        if !input && collected.is_none() {
+            continue;
+        }
+        if old_pos.is_expired() {
+            // an expired pointer outside the inputs is never read again
+            if input {
+                expired.push((entry.key().clone(), old_pos));
+            }
+            continue;
+        }
+        let (record, blob) = match collected {
+            Some(blob) => {
+                let blob =
+                    blobs.move_value(reader, path, old_pos, blob, cipher.as_ref(), options)?;
+                let record = Command::set_blob(entry.key().clone(), old_pos.expires_at, blob)
+                    .encode(old_pos.seq, cipher.as_ref());
+                (record, Some(blob))
+            }
+            None => {
+                let (record, old_cipher) = reader.read_and(old_pos, |record, cipher| {
+                    Ok((record.to_vec(), cipher.cloned()))
+                })?;
+                let record = rewrite(
+                    record,
+                    old_pos,
+                    old_cipher.as_ref(),
+                    cipher.as_ref(),
+                    options,
+                )?;
+                (record, old_pos.blob)
+            }
+        };
+        let (gen, range) = output.append(path, &record, &mut blobs, options)?;
+        let new_pos = CommandPos::new(gen, range, old_pos.expires_at, old_pos.seq, blob);
+        output.segment.hint_writer.add(entry.key(), &new_pos);
+        moved.push((entry.key().clone(), old_pos, new_pos));
+    }
+
+    if let Some(oldest_stale) = oldest_stale {
+        let newer_inputs = inputs.range(oldest_stale + 1..).copied();
+        for (key, seq) in latest_records(path, newer_inputs, options)? {
+            let removed = match index.get(&key) {
+                Some(entry) => entry.value().is_expired() && inputs.contains(&entry.value().gen),
+                None => true,
+            };
+            if !removed {
+                continue;
+            }
+            let record = Command::remove(key.clone()).encode(seq, cipher.as_ref());
+            let (gen, range) = output.append(path, &record, &mut blobs, options)?;
+            let remove_pos = CommandPos::new(gen, range, None, seq, None);
+            output.segment.hint_writer.add_remove(&key, &remove_pos);
+        }
+    }
+    let finished = output.finish(path, &mut blobs)?;
+    let compacted_size: u64 = finished.iter().map(|&(_, size)| size).sum();
+
+    let mut stale_size = 0;
+    let mut stale_files = Vec::new();
+    for &gen in &inputs {
+        stale_size += fs::metadata(log_path(path, gen))?.len();
+        stale_files.extend(gen_files(path, gen));
+    }
+    stale_files.extend(blobs.files.iter().map(|&file| blob_path(path, file)));
+    let blob_output_size = blobs.writer.as_ref().map(|writer| writer.pos);
+
+    // the outputs are tracked first, so writes made between the chunks below
+    // account for garbage in them
+    {
+        let mut writer = writer.lock().unwrap();
+        writer.log_size += compacted_size;
+        for &(gen, size) in &finished {
+            // the removes that were kept are still needed, they are not garbage
+            writer
+                .segments
+                .insert(gen, SegmentStats::new(size, cipher.as_ref()));
+        }
+        if let Some(size) = blob_output_size {
+            writer.blobs.insert(
+                blobs.output,
+                BlobStats {
+                    size,
+                    garbage: 0,
+                    key_id: cipher.as_ref().map(Cipher::key_id),
+                },
+            );
+        }
+    }
+
+    let mut moved = moved.into_iter().peekable();
+    while moved.peek().is_some() {
+        let mut writer = writer.lock().unwrap();
+        reader.remaps.start();
+        for (key, old_pos, new_pos) in moved.by_ref().take(REMAP_CHUNK) {
+            match index.get(&key) {
+                Some(entry) if *entry.value() == old_pos => {
+                    index.insert(key, new_pos);
+                    // a pointer moved out of a generation left alone
+                    writer.mark_segment_garbage(old_pos.gen, old_pos.len);
+                }
+                // overwritten or removed while we were copying
+                _ => {
+                    writer.mark_segment_garbage(new_pos.gen, new_pos.len);
+                    if let Some(blob) = new_pos.blob.filter(|_| new_pos.blob != old_pos.blob) {
+                        writer.mark_blob_garbage(blob);
+                    }
+                }
+            }
+        }
+        reader.remaps.finish();
+    }
+    // expired keys are left behind, their records go away with the inputs
+    let mut expired = expired.into_iter().peekable();
+    while expired.peek().is_some() {
+        let mut writer = writer.lock().unwrap();
+        for (key, old_pos) in expired.by_ref().take(REMAP_CHUNK) {
+            if index
+                .get(&key)
+                .is_some_and(|entry| *entry.value() == old_pos)
+            {
+                index.remove(&key);
+                if let Some(blob) = old_pos.blob {
+                    writer.mark_blob_garbage(blob);
+                }
+            }
+        }
+    }
+
+    let removable = {
+        let mut writer = writer.lock().unwrap();
+        writer.log_size -= stale_size;
+        for file in &blobs.files {
+            writer.blobs.remove(file);
+        }
+        for gen in &inputs {
+            writer.compaction_inputs.remove(gen);
+        }
+        versions.retire(stale_files)
+    };
+
+    reader.close(
+        inputs
+            .iter()
+            .map(|&gen| FileId::Log(gen))
+            .chain(blobs.files.iter().map(|&file| FileId::Blob(file))),
+    );
+
+    for file in removable {
+        fs::remove_file(file)?;
+    }
+    debug!(
+        "Compacted {} generations into {}.log",
+        inputs.len(),
+        first_output
+    );
+
+    Ok(())
+}
+
+/// Every key with a record in the logs of `gens`, with the sequence of its latest one.
+fn latest_records(
+    path: &Path,
+    gens: impl Iterator<Item = u64>,
+    options: &KvStoreOptions,
+) -> Result<BTreeMap<Vec<u8>, u64>> {
+    fn add(keys: &mut BTreeMap<Vec<u8>, u64>, cmd: Command, seq: u64) {
+        match cmd {
+            Command::Set { key, .. } | Command::Remove { key } => {
+                keys.insert(key, seq);
+            }
+            Command::Batch(cmds) => {
+                for (cmd, _) in cmds {
+                    add(keys, cmd, seq);
+                }
+            }
+        }
+    }
+
+    let mut keys = BTreeMap::new();
+    for gen in gens {
+        let mut reader = BufReaderWithPos::with_capacity(
+            options.read_buffer_size,
+            File::open(log_path(path, gen))?,
+        )?;
+        let header = read_file_header(&mut reader, gen, options)?;
+        let mut pos = reader.seek(SeekFrom::Start(header.len))?;
+        while let Some(Record { cmd, seq, len }) =
+            read_record(&mut reader, header.cipher.as_ref()).map_err(|e| e.at(gen, pos))?
+        {
+            add(&mut keys, cmd, seq);
+            pos += len;
+        }
+    }
+    Ok(keys)
+}
+
+/// Re-encode the set record at `pos`, or the value it points to, sealed with
+/// `old_cipher`, if its value is not compressed the way `options` ask or it is not
+/// sealed with `cipher`. Any other record is copied as it is.
+fn rewrite(
+    record: Vec<u8>,
+    pos: CommandPos,
+    old_cipher: Option<&Cipher>,
+    cipher: Option<&Cipher>,
+    options: &KvStoreOptions,
+) -> Result<Vec<u8>> {
+    let rekey = old_cipher.map(Cipher::key_id) != cipher.map(Cipher::key_id);
+    let cmd = decode_record(&record, old_cipher).map_err(|e| e.at(pos.gen, pos.pos))?;
+    // pointers to blobs have no value to compress
+    let recompress = matches!(
+        &cmd,
+        Command::Set { compression, blob: None, .. } if *compression != options.compression
+    );
+    match cmd {
+        Command::Set { .. } if rekey || recompress => {
+            let cmd = cmd.decompress().map_err(|e| e.at(pos.gen, pos.pos))?;
+            Ok(cmd.compress(options)?.encode(pos.seq, cipher))
+        }
+        _ => Ok(record),
+    }
+}
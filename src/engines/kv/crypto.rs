@@ -0,0 +1,84 @@
+//! Authenticated encryption of log records and hints.
+//!
+//! Every sealed buffer is `| nonce (24) | ciphertext | tag (16) |`, encrypted with
+//! XChaCha20-Poly1305 under a random nonce.
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+const NONCE_LEN: usize = 24;
+/// length of the value that tells whether a key is the one a file was written with
+pub(super) const KEY_CHECK_LEN: usize = 16;
+
+/// A key ready to seal and open buffers
+#[derive(Clone)]
+pub(super) struct Cipher {
+    key_id: u32,
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub(super) fn new(key_id: u32, key: &[u8; 32]) -> Cipher {
+        Cipher {
+            key_id,
+            aead: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    pub(super) fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// a value derived from the key, stored in file headers to detect a wrong key
+    pub(super) fn key_check(&self) -> [u8; KEY_CHECK_LEN] {
+        // the message is always the same, so the fixed nonce gives nothing away
+        let tag = self
+            .aead
+            .encrypt(
+                &XNonce::default(),
+                Payload {
+                    msg: &[],
+                    aad: b"kvs key check",
+                },
+            )
+            .expect("sealing never fails");
+        tag.as_slice().try_into().unwrap()
+    }
+
+    /// encrypt `plaintext` under a fresh nonce, authenticating `aad` along with it
+    pub(super) fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("sealing never fails");
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// decrypt a buffer made by `seal`, `None` if it was not sealed with this key
+    /// and `aad` or was tampered with
+    pub(super) fn open(&self, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
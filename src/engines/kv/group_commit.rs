@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use crossbeam::channel::Sender;
+
+use super::record::Command;
+use super::KvStoreWriter;
+use crate::{KvsError, Result};
+
+/// A write waiting for the next group commit
+pub(super) struct PendingWrite {
+    pub(super) cmd: Command,
+    /// receives the outcome once the group is durable
+    pub(super) done: Sender<Result<()>>,
+}
+
+impl KvStoreWriter {
+    /// Append every write of `group`, sync once and only then acknowledge them.
+    ///
+    /// Every write of the group, the leader's included, learns its outcome through
+    /// its `done` channel.
+    pub(super) fn commit_group(&mut self, group: Vec<PendingWrite>) {
+        // whether a key exists once the earlier writes of this group are applied
+        let mut exists: HashMap<Vec<u8>, bool> = HashMap::new();
+        let mut written = Vec::new();
+        let mut failure = None;
+
+        for PendingWrite { cmd, done } in group {
+            if failure.is_some() {
+                let _ = done.send(Err(group_failed(&failure)));
+                continue;
+            }
+            match &cmd {
+                Command::Set { key, .. } => {
+                    exists.insert(key.clone(), true);
+                }
+                Command::Remove { key } => {
+                    let present = match exists.get(key) {
+                        Some(&present) => present,
+                        None => self.contains(key),
+                    };
+                    if !present {
+                        let _ = done.send(Err(KvsError::KeyNotFound));
+                        continue;
+                    }
+                    exists.insert(key.clone(), false);
+                }
+                // removing a missing key is fine within a batch
+                Command::Batch(cmds) => {
+                    for (cmd, _) in cmds {
+                        match cmd {
+                            Command::Set { key, .. } => exists.insert(key.clone(), true),
+                            Command::Remove { key } => exists.insert(key.clone(), false),
+                            Command::Batch(_) => unreachable!("batches do not nest"),
+                        };
+                    }
+                }
+            }
+            match self.write_command(cmd) {
+                Ok((cmd, seq, range)) => written.push((cmd, seq, range, done)),
+                Err(e) => {
+                    let _ = done.send(Err(e));
+                    failure = Some("an earlier write of the group failed".to_owned());
+                }
+            }
+        }
+
+        if failure.is_none() {
+            if let Err(e) = self.commit_writes() {
+                failure = Some(format!("syncing the group failed: {}", e));
+            }
+        }
+        for (cmd, seq, range, done) in written {
+            if failure.is_some() {
+                let _ = done.send(Err(group_failed(&failure)));
+            } else {
+                self.index_command(cmd, seq, range);
+                let _ = done.send(Ok(()));
+            }
+        }
+        self.after_write();
+    }
+}
+
+fn group_failed(failure: &Option<String>) -> KvsError {
+    KvsError::StringError(failure.clone().expect("the group did not fail"))
+}
@@ -0,0 +1,229 @@
+//! Hint files written next to compacted generations.
+//!
+//! A hint file lists where every key of its generation lives, along with the
+//! removes the generation has to keep, so `KvStore::open` can rebuild the index for
+//! that generation without reading the log itself.
+//!
+//! ```text
+//! | magic "KVSH" (4) | format version, u32 LE (4) | gen, u64 LE (8) |
+//! | flags, u32 LE (4) | key id, u32 LE (4) |
+//! | key len, u32 LE (4) | pos, u64 LE (8) | len, u64 LE (8) | expires at, u64 LE (8) |
+//! | sequence, u64 LE (8) | blob file, u64 LE (8) | blob pos, u64 LE (8) |
+//! | blob len, u64 LE (8) | kind, u8 (1) | key | ...
+//! | crc32 of everything above, u32 LE (4) |
+//! ```
+//!
+//! An expiry of 0 means the key never expires, a blob length of 0 that the value is
+//! in the log. The kind tells a set from a remove. With `FLAG_ENCRYPTED` the entries are
+//! sealed as a whole with the key of the generation, the header is the associated
+//! data. Hints of older versions are ignored.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::blob::BlobPos;
+use super::crypto::Cipher;
+use super::options::KvStoreOptions;
+use super::CommandPos;
+use crate::Result;
+
+const MAGIC: [u8; 4] = *b"KVSH";
+const FORMAT_VERSION: u32 = 6;
+const HEADER_LEN: usize = 24;
+const ENTRY_HEADER_LEN: usize = 61;
+
+const KIND_SET: u8 = 0;
+const KIND_REMOVE: u8 = 1;
+
+const FLAG_ENCRYPTED: u32 = 0b1;
+
+pub(super) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+/// One key of a hinted generation
+pub(super) struct HintEntry {
+    pub(super) key: Vec<u8>,
+    pub(super) pos: u64,
+    pub(super) len: u64,
+    pub(super) expires_at: Option<u64>,
+    pub(super) seq: u64,
+    pub(super) blob: Option<BlobPos>,
+    /// whether the entry is a remove, which only has a position and a sequence
+    pub(super) removed: bool,
+}
+
+/// Collects the hint of a compacted generation while it is written.
+///
+/// The hint is written to a temporary file and only renamed into place by
+/// `finish`, so a crash never leaves a partial hint behind.
+pub(super) struct HintWriter {
+    gen: u64,
+    entries: Vec<u8>,
+    cipher: Option<Cipher>,
+    dir: PathBuf,
+}
+
+impl HintWriter {
+    /// start the hint of `gen`, sealed with `cipher` if there is one
+    pub(super) fn new(dir: &Path, gen: u64, cipher: Option<Cipher>) -> HintWriter {
+        HintWriter {
+            gen,
+            entries: Vec::new(),
+            cipher,
+            dir: dir.to_owned(),
+        }
+    }
+
+    /// note that `key` lives at `cmd_pos` of the generation
+    pub(super) fn add(&mut self, key: &[u8], cmd_pos: &CommandPos) {
+        self.push(key, cmd_pos, KIND_SET);
+    }
+
+    /// note that `key` is removed by the record at `cmd_pos` of the generation
+    pub(super) fn add_remove(&mut self, key: &[u8], cmd_pos: &CommandPos) {
+        self.push(key, cmd_pos, KIND_REMOVE);
+    }
+
+    fn push(&mut self, key: &[u8], cmd_pos: &CommandPos, kind: u8) {
+        let blob = cmd_pos.blob.unwrap_or(BlobPos {
+            file: 0,
+            pos: 0,
+            len: 0,
+        });
+        self.entries
+            .extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.entries.extend_from_slice(&cmd_pos.pos.to_le_bytes());
+        self.entries.extend_from_slice(&cmd_pos.len.to_le_bytes());
+        self.entries
+            .extend_from_slice(&cmd_pos.expires_at.unwrap_or(0).to_le_bytes());
+        self.entries.extend_from_slice(&cmd_pos.seq.to_le_bytes());
+        self.entries.extend_from_slice(&blob.file.to_le_bytes());
+        self.entries.extend_from_slice(&blob.pos.to_le_bytes());
+        self.entries.extend_from_slice(&blob.len.to_le_bytes());
+        self.entries.push(kind);
+        self.entries.extend_from_slice(key);
+    }
+
+    /// Write the hint and move it into place.
+    ///
+    /// Must only be called once the log of the generation is durable.
+    pub(super) fn finish(self) -> Result<()> {
+        let (flags, key_id) = match &self.cipher {
+            Some(cipher) => (FLAG_ENCRYPTED, cipher.key_id()),
+            None => (0, 0),
+        };
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.entries.len() + 4);
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        buf.extend_from_slice(&self.gen.to_le_bytes());
+        buf.extend_from_slice(&flags.to_le_bytes());
+        buf.extend_from_slice(&key_id.to_le_bytes());
+        match &self.cipher {
+            Some(cipher) => {
+                let sealed = cipher.seal(&self.entries, &buf);
+                buf.extend_from_slice(&sealed);
+            }
+            None => buf.extend_from_slice(&self.entries),
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+
+        let path = hint_path(&self.dir, self.gen);
+        let tmp_path = path.with_extension("hint.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// Read the hint file of `gen`, opening it with a key from `options`.
+///
+/// Returns `None` if there is no usable hint, in which case the log has to be
+/// replayed instead.
+pub(super) fn read_hint(
+    dir: &Path,
+    gen: u64,
+    options: &KvStoreOptions,
+) -> Result<Option<Vec<HintEntry>>> {
+    let path = hint_path(dir, gen);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let buf = fs::read(&path)?;
+    if buf.len() < HEADER_LEN + 4 {
+        return Ok(None);
+    }
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap())
+        || body[0..4] != MAGIC
+        || u32::from_le_bytes(body[4..8].try_into().unwrap()) != FORMAT_VERSION
+        || u64::from_le_bytes(body[8..16].try_into().unwrap()) != gen
+    {
+        return Ok(None);
+    }
+
+    let (header, entries_buf) = body.split_at(HEADER_LEN);
+    let flags = u32::from_le_bytes(header[16..20].try_into().unwrap());
+    let key_id = u32::from_le_bytes(header[20..24].try_into().unwrap());
+    let opened;
+    let mut rest = match flags {
+        0 => entries_buf,
+        // a wrong key shows up when the log is replayed instead
+        FLAG_ENCRYPTED => match options
+            .cipher_for(key_id)
+            .and_then(|cipher| cipher.open(entries_buf, header))
+        {
+            Some(entries) => {
+                opened = entries;
+                &opened[..]
+            }
+            None => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < ENTRY_HEADER_LEN {
+            return Ok(None);
+        }
+        let key_len = u32::from_le_bytes(rest[0..4].try_into().unwrap()) as usize;
+        let pos = u64::from_le_bytes(rest[4..12].try_into().unwrap());
+        let len = u64::from_le_bytes(rest[12..20].try_into().unwrap());
+        let expires_at = match u64::from_le_bytes(rest[20..28].try_into().unwrap()) {
+            0 => None,
+            expires_at => Some(expires_at),
+        };
+        let seq = u64::from_le_bytes(rest[28..36].try_into().unwrap());
+        let blob = BlobPos {
+            file: u64::from_le_bytes(rest[36..44].try_into().unwrap()),
+            pos: u64::from_le_bytes(rest[44..52].try_into().unwrap()),
+            len: u64::from_le_bytes(rest[52..60].try_into().unwrap()),
+        };
+        let removed = match rest[60] {
+            KIND_SET => false,
+            KIND_REMOVE => true,
+            _ => return Ok(None),
+        };
+        rest = &rest[ENTRY_HEADER_LEN..];
+        if rest.len() < key_len {
+            return Ok(None);
+        }
+        let key = rest[..key_len].to_vec();
+        rest = &rest[key_len..];
+        entries.push(HintEntry {
+            key,
+            pos,
+            len,
+            expires_at,
+            seq,
+            blob: Some(blob).filter(|blob| blob.len > 0),
+            removed,
+        });
+    }
+    Ok(Some(entries))
+}
@@ -0,0 +1,65 @@
+//! Migration of the logs written by earlier releases.
+//!
+//! Those logs hold JSON commands back to back, without a file header. The first
+//! writable open rewrites every such generation in the current format before it
+//! is loaded, each command as a record of sequence 0 since they all predate any
+//! sequenced write. A generation is written to a temporary file that is renamed
+//! over the old log, so a crash leaves it either migrated or untouched.
+
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::Deserializer;
+
+use super::options::KvStoreOptions;
+use super::record::Command;
+use super::{log_path, open_log_writer, sorted_gen_list};
+use crate::Result;
+
+/// A command as earlier releases wrote it
+#[derive(Deserialize)]
+enum JsonCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// The generations in `dir` whose logs are still in the JSON format.
+///
+/// Earlier releases left an empty log behind on every open. An empty newest log
+/// is left to `KvStore::open`, which removes it either way.
+pub(super) fn json_logs(dir: &Path) -> Result<Vec<u64>> {
+    let gen_list = sorted_gen_list(dir)?;
+    let mut gens = Vec::new();
+    for (i, &gen) in gen_list.iter().enumerate() {
+        let newest = i + 1 == gen_list.len();
+        let mut first = Vec::new();
+        File::open(log_path(dir, gen))?
+            .take(1)
+            .read_to_end(&mut first)?;
+        if first == b"{" || (first.is_empty() && !newest) {
+            gens.push(gen);
+        }
+    }
+    Ok(gens)
+}
+
+/// Rewrite the JSON log of `gen` in the current format, as `options` ask.
+pub(super) fn migrate_json_log(dir: &Path, gen: u64, options: &KvStoreOptions) -> Result<()> {
+    let log = log_path(dir, gen);
+    let tmp_log = log.with_extension("log.tmp");
+    let cipher = options.cipher();
+    let mut writer = open_log_writer(&tmp_log, options)?;
+    let reader = BufReader::new(File::open(&log)?);
+    for cmd in Deserializer::from_reader(reader).into_iter::<JsonCommand>() {
+        let cmd = match cmd? {
+            JsonCommand::Set { key, value } => Command::set(key.into_bytes(), value.into_bytes()),
+            JsonCommand::Remove { key } => Command::remove(key.into_bytes()),
+        };
+        writer.write_all(&cmd.compress(options)?.encode(0, cipher.as_ref()))?;
+    }
+    writer.sync()?;
+    fs::rename(&tmp_log, &log)?;
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Read-only memory maps of sealed log files.
+//!
+//! Only generations that are no longer written to get mapped. Their files never
+//! change again and are only ever removed, which leaves an existing mapping intact,
+//! so a slice of the mapping stays valid for as long as the mapping lives.
+
+use std::fs::File;
+use std::io;
+
+/// A read-only mapping of a whole file, unmapped when dropped
+pub(super) struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// the mapping is read-only and owned, so it can be shared like a `Box<[u8]>`
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// map all of `file`, which must not change while it is mapped
+    #[cfg(unix)]
+    pub(super) fn map(file: &File) -> io::Result<Mmap> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr, len })
+    }
+
+    /// mapping is only supported on unix, reads fall back to positional reads
+    #[cfg(not(unix))]
+    pub(super) fn map(_file: &File) -> io::Result<Mmap> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub(super) fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
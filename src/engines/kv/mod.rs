@@ -0,0 +1,1564 @@
+use crossbeam_skiplist::SkipMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::{Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Sender};
+use fs2::FileExt;
+use log::{error, warn};
+
+use self::blob::{blob_path, load_blob_stats, read_blob, BlobPos, BlobStats};
+use self::cache::ValueCache;
+use self::compaction::{CompactionJob, CompactionMsg, Compactor, Remaps, SegmentStats};
+use self::crypto::Cipher;
+use self::group_commit::PendingWrite;
+use self::hint::{hint_path, read_hint, HintEntry};
+use self::legacy::{json_logs, migrate_json_log};
+use self::mmap::Mmap;
+pub use self::options::{CompactionTrigger, Compression, EncryptionKey, KvStoreOptions};
+use self::record::{
+    decode_record, decompress_value, ends_in_file_header, read_file_header, read_record,
+    write_file_header, Command, DecodeError, FileHeader, Record, RECORD_HEADER_LEN,
+};
+use self::scan::KvScan;
+pub use self::snapshot::Snapshot;
+use self::snapshot::{ReadView, Versions};
+use self::sweeper::sweep_expired;
+pub use self::transaction::Transaction;
+use crate::engines::{create_checkpoint_dir, expiry, BatchOp, ByteScan, EngineStats, PeriodicTask};
+use crate::{KvsEngine, KvsError, Result, SyncPolicy, WriteBatch};
+
+mod blob;
+mod cache;
+mod compaction;
+mod crypto;
+mod group_commit;
+mod hint;
+mod legacy;
+mod mmap;
+mod options;
+mod record;
+mod scan;
+mod snapshot;
+mod sweeper;
+mod transaction;
+
+/// The KvStore stores key/value pairs of bytes
+///
+/// The `String` methods of `KvsEngine` are convenience wrappers over the byte ones.
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # use std::env::current_dir;
+/// fn try_main() -> Result<()> {
+/// # use kvs::KvsEngine;
+/// # let mut store = KvStore::open(current_dir()?)?;
+/// # store.set("key1".to_owned(), "value1".to_owned());
+/// # let val = store.get("key1".to_owned())?;
+/// # assert_eq!(val, Some("value1".to_owned()));
+/// # Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct KvStore {
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    reader: KvStoreReader,
+    /// `None` when the store is opened read-only
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    path: Arc<PathBuf>,
+    /// only held for its `Drop`, set with `SyncPolicy::Interval`
+    #[allow(dead_code)]
+    syncer: Option<Arc<PeriodicTask>>,
+    /// only held for its `Drop`, which stops sweeping expired keys with the last clone
+    #[allow(dead_code)]
+    sweeper: Option<Arc<PeriodicTask>>,
+    /// only held for its `Drop`, which stops the compaction thread with the last
+    /// clone, after the threads above that can start a compaction stopped
+    #[allow(dead_code)]
+    compactor: Option<Arc<Compactor>>,
+    /// writes waiting for the next group commit, set with `SyncPolicy::GroupCommit`
+    group_commit: Option<Arc<Mutex<Vec<PendingWrite>>>>,
+    versions: Arc<Versions>,
+    /// only held for its `Drop`, which releases the directory lock with the last
+    /// clone, after the threads above stopped
+    #[allow(dead_code)]
+    lock: Option<Arc<File>>,
+}
+
+impl KvStore {
+    /// new a KvStore with the log in the specific filePath
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// open a KvStore in the given directory with custom settings
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = Arc::new(path.into());
+        let options = Arc::new(options);
+        if !options.read_only {
+            fs::create_dir_all(&*path)?;
+        }
+        let lock = lock_dir(&path, options.read_only)?.map(Arc::new);
+        if !options.read_only {
+            remove_tmp_files(&path)?;
+        }
+        for gen in json_logs(&path)? {
+            if options.read_only {
+                return Err(KvsError::JsonLog(gen));
+            }
+            migrate_json_log(&path, gen, &options)?;
+        }
+
+        let mut files = BTreeMap::new();
+        let index = Arc::new(SkipMap::new());
+
+        let gen_list = sorted_gen_list(&path)?;
+        let mut segments = BTreeMap::new();
+        let mut garbage = BTreeMap::new();
+        let mut log_size = 0;
+        let mut last_seq = 0;
+        for (i, &gen) in gen_list.iter().enumerate() {
+            // only the newest generation can have been cut off by a crash
+            let newest = i + 1 == gen_list.len();
+            let log = log_path(&path, gen);
+            if let Some(entries) = read_hint(&path, gen, &options)? {
+                load_hint(gen, entries, &index, &mut garbage, &mut last_seq);
+                let header = read_file_header(&mut File::open(&log)?, gen, &options)?;
+                let size = fs::metadata(&log)?.len();
+                segments.insert(gen, SegmentStats::new(size, header.cipher.as_ref()));
+                log_size += size;
+                continue;
+            }
+            if newest && ends_in_file_header(&log)? {
+                if !options.read_only {
+                    warn!("Removing {}.log, it ends inside its file header", gen);
+                    fs::remove_file(&log)?;
+                }
+                continue;
+            }
+            let mut reader =
+                BufReaderWithPos::with_capacity(options.read_buffer_size, File::open(&log)?)?;
+            let header = read_file_header(&mut reader, gen, &options)?;
+            let torn_at = load(
+                gen,
+                &mut reader,
+                &header,
+                &index,
+                &mut garbage,
+                &mut last_seq,
+                newest,
+            )?;
+            if let Some(valid_len) = torn_at {
+                if !options.read_only {
+                    truncate_log(&log, gen, valid_len)?;
+                }
+            }
+            let size = fs::metadata(&log)?.len();
+            segments.insert(gen, SegmentStats::new(size, header.cipher.as_ref()));
+            log_size += size;
+            let file = reader.reader.into_inner();
+            files.insert(
+                FileId::Log(gen),
+                Arc::new(LogFile {
+                    file,
+                    cipher: header.cipher,
+                    map: OnceLock::new(),
+                }),
+            );
+        }
+        for (gen, stale) in garbage {
+            if let Some(stats) = segments.get_mut(&gen) {
+                stats.garbage += stale;
+            }
+        }
+
+        let blobs = load_blob_stats(&path, &index, &options)?;
+        let versions = Arc::new(Versions::new(last_seq));
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let reader = KvStoreReader {
+            path: Arc::clone(&path),
+            files: Arc::new(RwLock::new(LogFiles {
+                open: files,
+                closed: BTreeSet::new(),
+            })),
+            // a read-only store writes to no generation
+            active_gen: Arc::new(AtomicU64::new(if options.read_only {
+                0
+            } else {
+                current_gen
+            })),
+            active_blob: Arc::new(AtomicU64::new(0)),
+            remaps: Arc::default(),
+            cache: options
+                .value_cache_size
+                .map(|size| Arc::new(ValueCache::new(size))),
+            options: Arc::clone(&options),
+        };
+
+        if options.read_only {
+            return Ok(KvStore {
+                index,
+                reader,
+                writer: None,
+                path,
+                compactor: None,
+                syncer: None,
+                sweeper: None,
+                group_commit: None,
+                versions,
+                lock,
+            });
+        }
+
+        let writer = new_log_file(&path, current_gen, &options)?;
+        log_size += writer.pos;
+        let cipher = options.cipher();
+        segments.insert(current_gen, SegmentStats::new(writer.pos, cipher.as_ref()));
+
+        let (compaction_tx, compaction_rx) = Compactor::channel();
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
+            writer,
+            current_gen,
+            active_gen: Arc::clone(&reader.active_gen),
+            active_blob: Arc::clone(&reader.active_blob),
+            segments,
+            log_size,
+            unsynced: false,
+            compacting: false,
+            compaction_inputs: BTreeSet::new(),
+            compactions: 0,
+            last_compaction: None,
+            blob_writer: None,
+            next_blob_file: blobs.keys().next_back().unwrap_or(&0) + 1,
+            blobs,
+            compaction_tx: compaction_tx.clone(),
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            versions: Arc::clone(&versions),
+            cipher,
+            options: Arc::clone(&options),
+        }));
+        let compactor = Compactor::spawn(
+            compaction_tx,
+            compaction_rx,
+            Arc::clone(&writer),
+            Arc::clone(&index),
+            reader.clone(),
+            Arc::clone(&versions),
+            Arc::clone(&path),
+        )?;
+
+        let syncer = match options.sync_policy {
+            SyncPolicy::Interval(interval) => {
+                let writer = Arc::clone(&writer);
+                let syncer = PeriodicTask::spawn("kvs-sync", interval, move |_| {
+                    // sync handles of the active files so writers are not blocked meanwhile
+                    let unsynced = writer.lock().unwrap().take_unsynced()?;
+                    for file in unsynced {
+                        file.sync_data()?;
+                    }
+                    Ok(())
+                })?;
+                Some(Arc::new(syncer))
+            }
+            _ => None,
+        };
+
+        let sweeper = {
+            let writer = Arc::clone(&writer);
+            let index = Arc::clone(&index);
+            // the compaction thread may be gone by the last sweep
+            PeriodicTask::spawn("kvs-ttl-sweep", options.ttl_sweep_interval, move |last| {
+                sweep_expired(&writer, &index, !last)
+            })?
+        };
+
+        let group_commit = match options.sync_policy {
+            SyncPolicy::GroupCommit => Some(Arc::new(Mutex::new(Vec::new()))),
+            _ => None,
+        };
+
+        Ok(KvStore {
+            index,
+            reader,
+            writer: Some(writer),
+            path,
+            compactor: Some(Arc::new(compactor)),
+            syncer,
+            sweeper: Some(Arc::new(sweeper)),
+            group_commit,
+            versions,
+            lock,
+        })
+    }
+
+    /// take a snapshot that keeps seeing the store as it is now
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        // no write may get between picking the sequence and registering the snapshot
+        let _writer = self.writer.as_ref().map(|writer| writer.lock().unwrap());
+        Ok(Snapshot::new(
+            Arc::clone(&self.index),
+            self.versions.pin(),
+            self.reader.clone(),
+        ))
+    }
+
+    /// start a transaction that reads the store as it is now
+    pub fn begin_transaction(&self) -> Result<Transaction> {
+        Ok(Transaction::new(self.clone(), self.snapshot()?))
+    }
+
+    /// Queue `cmd` for the next group commit and wait until it is durable.
+    ///
+    /// Whoever gets the writer lock next commits every queued command with a single
+    /// sync, so writers arriving while a sync is running share the following one.
+    fn submit(&self, cmd: Command) -> Result<()> {
+        let queue = self
+            .group_commit
+            .as_ref()
+            .expect("group commit is disabled");
+        let (done, result) = channel::bounded(1);
+        queue.lock().unwrap().push(PendingWrite { cmd, done });
+
+        let mut writer = self.writer()?;
+        let group = mem::take(&mut *queue.lock().unwrap());
+        // an empty queue means an earlier leader already committed our write
+        if !group.is_empty() {
+            writer.commit_group(group);
+        }
+        drop(writer);
+        result
+            .recv()
+            .map_err(|_| KvsError::StringError("the group commit was abandoned".to_owned()))?
+    }
+
+    /// apply a set or a batch, through the group commit if there is one
+    fn write(&self, cmd: Command) -> Result<()> {
+        if self.group_commit.is_some() {
+            return self.submit(cmd);
+        }
+        self.writer()?.apply(cmd)
+    }
+
+    fn writer(&self) -> Result<MutexGuard<'_, KvStoreWriter>> {
+        match &self.writer {
+            Some(writer) => Ok(writer.lock().unwrap()),
+            None => Err(KvsError::ReadOnly),
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(Command::set(key, value))
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write(Command::set_with_expiry(
+            key,
+            value,
+            expiry::expires_at(ttl),
+        ))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let cmds: Vec<Command> = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Command::set(key, value),
+                BatchOp::Remove { key } => Command::remove(key),
+            })
+            .collect();
+        if cmds.is_empty() {
+            return Ok(());
+        }
+        self.write(Command::batch(cmds))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        if self.group_commit.is_some() {
+            return self.submit(Command::remove(key));
+        }
+        self.writer()?.remove(key)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        // holding the writer keeps every other write out between the check and the swap
+        let mut writer = self.writer()?;
+        let current = self.reader.get(&self.index, &key)?;
+        if current != expected {
+            return Err(KvsError::CompareAndSwap { current });
+        }
+        match (new, current) {
+            (Some(value), _) => writer.apply(Command::set(key, value)),
+            (None, Some(_)) => writer.apply(Command::remove(key)),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// The index drops a key for a moment while its entry is replaced, so a read
+    /// racing a write of the same key may find it missing. Compactions moving the
+    /// key are waited out.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.reader.get(&self.index, &key)
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let lookup = || self.index.get(&key).map(|entry| *entry.value());
+        match self.reader.remaps.read(lookup, Option::is_some) {
+            Some(cmd_pos) if !cmd_pos.is_expired() => Ok(cmd_pos.expires_at.map(expiry::remaining)),
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ByteScan> {
+        Ok(ByteScan::new(KvScan::new(
+            ReadView::latest(Arc::clone(&self.index)),
+            self.reader.clone(),
+            range,
+        )))
+    }
+
+    /// A read-only store does not track its garbage or compact, it reports none.
+    fn stats(&self) -> Result<EngineStats> {
+        let mut stats = EngineStats::default();
+        for entry in self.index.iter() {
+            let cmd_pos = entry.value();
+            if !cmd_pos.is_expired() {
+                stats.live_keys += 1;
+                stats.live_bytes += cmd_pos.len + cmd_pos.blob.map_or(0, |blob| blob.len);
+            }
+        }
+        if let Some(writer) = &self.writer {
+            let writer = writer.lock().unwrap();
+            stats.dead_bytes = writer.uncompacted();
+            stats.garbage_by_gen = writer
+                .segments
+                .iter()
+                .filter(|(_, segment)| segment.garbage > 0)
+                .map(|(&gen, segment)| (gen, segment.garbage))
+                .collect();
+            stats.compactions = writer.compactions;
+            stats.last_compaction = writer.last_compaction;
+        }
+        stats.open_readers = self.reader.files.read().unwrap().open.len();
+        if let Some(cache) = &self.reader.cache {
+            stats.cache_hits = cache.hits();
+            stats.cache_misses = cache.misses();
+        }
+        stats.disk_size = dir_size(&self.path)?;
+        Ok(stats)
+    }
+
+    /// Writes made after the checkpoint starts are left out. Files that are no
+    /// longer written to are hard linked where the file system allows it.
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        create_checkpoint_dir(dest_dir)?;
+        // the files to copy, with the length to copy of those still being written
+        let mut files: Vec<(PathBuf, Option<u64>)> = Vec::new();
+        // keeps compactions from removing the files until they are copied
+        let _pin = match &self.writer {
+            Some(writer) => {
+                let mut writer = writer.lock().unwrap();
+                let writer = &mut *writer;
+                writer.writer.flush()?;
+                let active_blob = match &mut writer.blob_writer {
+                    Some((file, blob_writer)) => {
+                        blob_writer.flush()?;
+                        Some((*file, blob_writer.pos))
+                    }
+                    None => None,
+                };
+                let gens: BTreeSet<u64> = writer
+                    .segments
+                    .keys()
+                    .chain(&writer.compaction_inputs)
+                    .copied()
+                    .collect();
+                for gen in gens {
+                    if gen == writer.current_gen {
+                        files.push((log_path(&self.path, gen), Some(writer.writer.pos)));
+                    } else {
+                        files.extend(
+                            gen_files(&self.path, gen)
+                                .into_iter()
+                                .map(|file| (file, None)),
+                        );
+                    }
+                }
+                for &file in writer.blobs.keys() {
+                    let len = active_blob
+                        .filter(|&(active, _)| active == file)
+                        .map(|(_, pos)| pos);
+                    files.push((blob_path(&self.path, file), len));
+                }
+                Some(self.versions.pin())
+            }
+            None => {
+                // opening the copy trims the newest files if a crash cut them off,
+                // so those are copied rather than linked
+                let gens = sorted_gen_list(&self.path)?;
+                for &gen in &gens {
+                    let len = match gens.last() {
+                        Some(&newest) if newest == gen => {
+                            Some(fs::metadata(log_path(&self.path, gen))?.len())
+                        }
+                        _ => None,
+                    };
+                    let mut gen_files = gen_files(&self.path, gen).into_iter();
+                    files.extend(gen_files.next().map(|log| (log, len)));
+                    files.extend(gen_files.map(|hint| (hint, None)));
+                }
+                let blobs = sorted_file_ids(&self.path, "blob")?;
+                for &file in &blobs {
+                    let path = blob_path(&self.path, file);
+                    let len = match blobs.last() {
+                        Some(&newest) if newest == file => Some(fs::metadata(&path)?.len()),
+                        _ => None,
+                    };
+                    files.push((path, len));
+                }
+                None
+            }
+        };
+        for (file, len) in files {
+            let name = file.file_name().expect("store files have a name");
+            copy_file(&file, &dest_dir.join(name), len)?;
+        }
+        Ok(())
+    }
+}
+
+fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    sorted_file_ids(path, "log")
+}
+
+/// the numbers of the files named `<number>.<extension>` in `path`, in order
+fn sorted_file_ids(path: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// The log of `gen` together with its hint, if there is one.
+fn gen_files(dir: &Path, gen: u64) -> Vec<PathBuf> {
+    let hint = hint_path(dir, gen);
+    let mut files = vec![log_path(dir, gen)];
+    if hint.exists() {
+        files.push(hint);
+    }
+    files
+}
+
+/// the size of every file in `dir` together
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Take the lock of the store in `dir`, exclusive for writing and shared for
+/// reading, failing with `KvsError::Locked` if another open holds it.
+///
+/// A read-only open must not create the lock file, so it goes without a lock
+/// in a directory no writer opened yet.
+fn lock_dir(dir: &Path, read_only: bool) -> Result<Option<File>> {
+    let path = dir.join("LOCK");
+    let (file, locked) = if read_only {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let locked = FileExt::try_lock_shared(&file);
+        (file, locked)
+    } else {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        let locked = FileExt::try_lock_exclusive(&file);
+        (file, locked)
+    };
+    match locked {
+        Ok(()) => Ok(Some(file)),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Err(KvsError::Locked),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// copy the first `len` bytes of `src` to `dest`, or all of it when `len` is
+/// `None`, in which case the file never changes again and a hard link will do
+fn copy_file(src: &Path, dest: &Path, len: Option<u64>) -> Result<()> {
+    if len.is_none() && fs::hard_link(src, dest).is_ok() {
+        return Ok(());
+    }
+    let src = File::open(src)?;
+    let len = match len {
+        Some(len) => len,
+        None => src.metadata()?.len(),
+    };
+    let mut dest = File::create(dest)?;
+    io::copy(&mut (&src).take(len), &mut dest)?;
+    dest.sync_all()?;
+    Ok(())
+}
+
+/// Remove files left behind by a compaction that was interrupted.
+fn remove_tmp_files(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("tmp".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+fn new_log_file(dir: &Path, gen: u64, options: &KvStoreOptions) -> Result<BufWriterWithPos<File>> {
+    open_log_writer(&log_path(dir, gen), options)
+}
+
+/// open a log file for appending, new files are encrypted as `options` ask
+fn open_log_writer(path: &Path, options: &KvStoreOptions) -> Result<BufWriterWithPos<File>> {
+    let mut writer = BufWriterWithPos::with_capacity(
+        options.write_buffer_size,
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?,
+    )?;
+    if writer.pos == 0 {
+        write_file_header(&mut writer, options.cipher().as_ref())?;
+        writer.flush()?;
+    }
+    Ok(writer)
+}
+
+/// Replay the log of `gen` into `index`, adding the stale bytes found to `garbage`
+/// and raising `last_seq` to the latest sequence seen.
+///
+/// When `recover` is set, a torn or corrupted tail stops the replay instead of
+/// failing it, and the offset of the end of the last complete record is returned.
+/// A bad record that a valid one follows is no tail, it fails the replay all the
+/// same.
+fn load(
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    header: &FileHeader,
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    garbage: &mut BTreeMap<u64, u64>,
+    last_seq: &mut u64,
+    recover: bool,
+) -> Result<Option<u64>> {
+    let mut pos = reader.seek(SeekFrom::Start(header.len))?;
+
+    loop {
+        let Record { cmd, seq, len } = match read_record(reader, header.cipher.as_ref()) {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(e @ DecodeError::Truncated) | Err(e @ DecodeError::Checksum) if recover => {
+                if record_follows(reader, pos, header.cipher.as_ref())? {
+                    return Err(e.at(gen, pos));
+                }
+                warn!("{}", e.at(gen, pos));
+                return Ok(Some(pos));
+            }
+            Err(e) => return Err(e.at(gen, pos)),
+        };
+        let new_pos = pos + len;
+        load_command(index, garbage, gen, cmd, seq, pos..new_pos);
+        *last_seq = (*last_seq).max(seq);
+        pos = new_pos;
+    }
+
+    Ok(None)
+}
+
+/// Whether a valid record starts anywhere after the end of the bad record at `pos`.
+///
+/// A bad record running to the end of the log is a torn write, the bytes it
+/// claims are simply missing.
+fn record_follows(
+    reader: &mut BufReaderWithPos<File>,
+    pos: u64,
+    cipher: Option<&Cipher>,
+) -> Result<bool> {
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest)?;
+    if rest.len() < RECORD_HEADER_LEN {
+        return Ok(false);
+    }
+    let payload_len = u32::from_le_bytes(rest[0..4].try_into().unwrap()) as usize;
+    let end = RECORD_HEADER_LEN + payload_len;
+    Ok((end..rest.len())
+        .any(|start| matches!(read_record(&mut &rest[start..], cipher), Ok(Some(_)))))
+}
+
+/// Index a command found at `range` of `gen`, adding the stale bytes it leaves
+/// behind to `garbage`.
+fn load_command(
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    garbage: &mut BTreeMap<u64, u64>,
+    gen: u64,
+    cmd: Command,
+    seq: u64,
+    range: Range<u64>,
+) {
+    match cmd {
+        Command::Set {
+            key,
+            expires_at,
+            blob,
+            ..
+        } => load_set(
+            index,
+            garbage,
+            key,
+            CommandPos::new(gen, range, expires_at, seq, blob),
+        ),
+        Command::Remove { key } => {
+            if let Some(old_cmd) = index.remove(&key) {
+                add_garbage(garbage, *old_cmd.value());
+            }
+            *garbage.entry(gen).or_default() += range.end - range.start;
+        }
+        // only the records inside a batch are ever read, its header is stale right away
+        Command::Batch(cmds) => {
+            *garbage.entry(gen).or_default() += batch_header_len(&cmds, &range);
+            for (cmd, inner) in cmds {
+                let inner = range.start + inner.start..range.start + inner.end;
+                load_command(index, garbage, gen, cmd, seq, inner);
+            }
+        }
+    }
+}
+
+/// count the record at `cmd_pos` as stale
+fn add_garbage(garbage: &mut BTreeMap<u64, u64>, cmd_pos: CommandPos) {
+    *garbage.entry(cmd_pos.gen).or_default() += cmd_pos.len;
+}
+
+/// the bytes of a batch record at `range` that are not part of its inner records
+fn batch_header_len(cmds: &[(Command, Range<u64>)], range: &Range<u64>) -> u64 {
+    let inner_len: u64 = cmds.iter().map(|(_, inner)| inner.end - inner.start).sum();
+    range.end - range.start - inner_len
+}
+
+/// Fill `index` from the hint file of `gen` instead of replaying its log.
+fn load_hint(
+    gen: u64,
+    entries: Vec<HintEntry>,
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    garbage: &mut BTreeMap<u64, u64>,
+    last_seq: &mut u64,
+) {
+    for HintEntry {
+        key,
+        pos,
+        len,
+        expires_at,
+        seq,
+        blob,
+        removed,
+    } in entries
+    {
+        *last_seq = (*last_seq).max(seq);
+        // a compaction only keeps the removes that are still needed, they are not stale
+        if removed {
+            if let Some(old_cmd) = index.remove(&key) {
+                add_garbage(garbage, *old_cmd.value());
+            }
+            continue;
+        }
+        let cmd_pos = CommandPos::new(gen, pos..pos + len, expires_at, seq, blob);
+        load_set(index, garbage, key, cmd_pos);
+    }
+}
+
+/// Index a set found while loading, adding the stale bytes it leaves behind to
+/// `garbage`.
+///
+/// A set that has expired by now is as good as a remove.
+fn load_set(
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    garbage: &mut BTreeMap<u64, u64>,
+    key: Vec<u8>,
+    cmd_pos: CommandPos,
+) {
+    let old_cmd = if cmd_pos.is_expired() {
+        add_garbage(garbage, cmd_pos);
+        index.remove(&key).map(|old_cmd| *old_cmd.value())
+    } else {
+        let old_cmd = index.get(&key).map(|old_cmd| *old_cmd.value());
+        index.insert(key, cmd_pos);
+        old_cmd
+    };
+    if let Some(old_cmd) = old_cmd {
+        add_garbage(garbage, old_cmd);
+    }
+}
+
+/// Cut the log of `gen` back to its last complete record.
+fn truncate_log(log: &Path, gen: u64, valid_len: u64) -> Result<()> {
+    let file = fs::OpenOptions::new().write(true).open(log)?;
+    let dropped = file.metadata()?.len() - valid_len;
+    file.set_len(valid_len)?;
+    file.sync_all()?;
+    warn!(
+        "Dropped {} bytes of incomplete writes at the end of {}.log",
+        dropped, gen
+    );
+    Ok(())
+}
+
+/// A file records are read from, a log generation or a blob file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum FileId {
+    Log(u64),
+    Blob(u64),
+}
+
+impl FileId {
+    fn path(self, dir: &Path) -> PathBuf {
+        match self {
+            FileId::Log(gen) => log_path(dir, gen),
+            FileId::Blob(file) => blob_path(dir, file),
+        }
+    }
+
+    /// the number in the name of the file
+    fn number(self) -> u64 {
+        match self {
+            FileId::Log(gen) => gen,
+            FileId::Blob(file) => file,
+        }
+    }
+}
+
+impl fmt::Display for FileId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileId::Log(gen) => write!(f, "{}.log", gen),
+            FileId::Blob(file) => write!(f, "{}.blob", file),
+        }
+    }
+}
+
+/// An open log or blob file with the key its records are sealed with
+///
+/// Readers hold on to it for the length of a read, so a compaction dropping it
+/// only closes the file and unmaps it once the last of them is done.
+struct LogFile {
+    file: File,
+    cipher: Option<Cipher>,
+    /// the whole file, mapped on the first read once it is sealed
+    map: OnceLock<Option<Mmap>>,
+}
+
+/// The log and blob files held open for reading
+struct LogFiles {
+    open: BTreeMap<FileId, Arc<LogFile>>,
+    /// files a compaction removed, snapshots still seeing one of them open it for
+    /// every read so no handle outlives the file
+    closed: BTreeSet<FileId>,
+}
+
+/// Reads records with positional reads, through one handle per log generation
+/// and blob file that every clone shares
+#[derive(Clone)]
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    files: Arc<RwLock<LogFiles>>,
+    /// the generation the writer appends to, every other one is sealed
+    active_gen: Arc<AtomicU64>,
+    /// the blob file the writer appends to, 0 for none
+    active_blob: Arc<AtomicU64>,
+    /// remaps of index entries by compactions, which readers of the index wait out
+    remaps: Arc<Remaps>,
+    /// recently read values, set with `KvStoreOptions::value_cache_size`
+    cache: Option<Arc<ValueCache>>,
+    options: Arc<KvStoreOptions>,
+}
+
+impl KvStoreReader {
+    /// drop the handles of the files a compaction removed, and the cached values
+    /// of its log generations
+    fn close(&self, ids: impl IntoIterator<Item = FileId>) {
+        let mut files = self.files.write().unwrap();
+        let mut gens = BTreeSet::new();
+        for id in ids {
+            files.open.remove(&id);
+            files.closed.insert(id);
+            if let FileId::Log(gen) = id {
+                gens.insert(gen);
+            }
+        }
+        if let Some(cache) = &self.cache {
+            cache.remove_gens(&gens);
+        }
+    }
+
+    /// the file `id`, opened on first use
+    fn file(&self, id: FileId) -> Result<Arc<LogFile>> {
+        if let Some(file) = self.files.read().unwrap().open.get(&id) {
+            return Ok(Arc::clone(file));
+        }
+        let mut file = File::open(id.path(&self.path))?;
+        let header = read_file_header(&mut file, id.number(), &self.options)?;
+        let file = Arc::new(LogFile {
+            file,
+            cipher: header.cipher,
+            map: OnceLock::new(),
+        });
+        let mut files = self.files.write().unwrap();
+        if files.closed.contains(&id) {
+            return Ok(file);
+        }
+        // another reader may have opened it meanwhile, either handle will do
+        Ok(Arc::clone(files.open.entry(id).or_insert(file)))
+    }
+
+    /// whether a compaction removed the log of `gen`
+    fn is_closed(&self, gen: u64) -> bool {
+        self.files
+            .read()
+            .unwrap()
+            .closed
+            .contains(&FileId::Log(gen))
+    }
+
+    /// whether the writer still appends to the file `id`
+    fn is_active(&self, id: FileId) -> bool {
+        match id {
+            FileId::Log(gen) => gen == self.active_gen.load(Ordering::SeqCst),
+            FileId::Blob(file) => file == self.active_blob.load(Ordering::SeqCst),
+        }
+    }
+
+    /// the mapping of the file `id`, if reads are served from mappings and the
+    /// file is sealed
+    fn map<'a>(&self, id: FileId, log: &'a LogFile) -> Option<&'a Mmap> {
+        if !self.options.mmap_reads || self.is_active(id) {
+            return None;
+        }
+        log.map
+            .get_or_init(|| match Mmap::map(&log.file) {
+                Ok(map) => Some(map),
+                Err(e) => {
+                    warn!("Mapping {} failed, reading it instead: {}", id, e);
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    /// run `f` on the `len` bytes at `pos` in the file `id` and the key of the file
+    fn read_at<F, R>(&self, id: FileId, pos: u64, len: u64, f: F) -> Result<R>
+    where
+        F: FnOnce(&[u8], Option<&Cipher>) -> Result<R>,
+    {
+        let log = self.file(id)?;
+        let range = pos as usize..(pos + len) as usize;
+        let mapped = self.map(id, &log).and_then(|map| map.as_slice().get(range));
+        if let Some(record) = mapped {
+            return f(record, log.cipher.as_ref());
+        }
+        let mut record = vec![0; len as usize];
+        read_exact_at(&log.file, &mut record, pos)?;
+        f(&record, log.cipher.as_ref())
+    }
+
+    /// run `f` on the record at `cmd_pos` and the key of its file
+    fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(&[u8], Option<&Cipher>) -> Result<R>,
+    {
+        self.read_at(FileId::Log(cmd_pos.gen), cmd_pos.pos, cmd_pos.len, f)
+    }
+
+    /// run `f` on the record of the value at `blob` and the key of its file
+    fn read_blob_and<F, R>(&self, blob: BlobPos, f: F) -> Result<R>
+    where
+        F: FnOnce(&[u8], Option<&Cipher>) -> Result<R>,
+    {
+        self.read_at(FileId::Blob(blob.file), blob.pos, blob.len, f)
+    }
+
+    /// read the value `index` holds for `key`
+    fn get(&self, index: &SkipMap<Vec<u8>, CommandPos>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_with(|| index.get(key).map(|entry| *entry.value()))
+    }
+
+    /// read the value of the record `lookup` points to
+    fn get_with(&self, lookup: impl Fn() -> Option<CommandPos>) -> Result<Option<Vec<u8>>> {
+        loop {
+            let cmd_pos = match self.remaps.read(&lookup, Option::is_some) {
+                Some(cmd_pos) if !cmd_pos.is_expired() => cmd_pos,
+                _ => return Ok(None),
+            };
+            let cache = self.cache.as_deref();
+            if let Some(value) = cache.and_then(|cache| cache.get(&cmd_pos)) {
+                return Ok(Some(value));
+            }
+            let cmd = match cmd_pos.blob {
+                Some(_) => read_blob(self, cmd_pos),
+                None => self.read_command(cmd_pos),
+            };
+            match cmd {
+                Ok(Command::Set {
+                    value, compression, ..
+                }) => {
+                    let value = match compression {
+                        Some(compression) => decompress_value(compression, &value)
+                            .map_err(|e| e.at(cmd_pos.gen, cmd_pos.pos))?,
+                        None => value,
+                    };
+                    if let Some(cache) = cache.filter(|_| !self.is_closed(cmd_pos.gen)) {
+                        cache.insert(&cmd_pos, value.clone());
+                    }
+                    return Ok(Some(value));
+                }
+                Ok(_) => return Err(KvsError::UnexpectedCommandType),
+                // a compaction moved the record and removed its file meanwhile,
+                // the index already points to the new position
+                Err(_) if lookup() != Some(cmd_pos) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
+        self.read_and(cmd_pos, |record, cipher| {
+            decode_record(record, cipher).map_err(|e| e.at(cmd_pos.gen, cmd_pos.pos))
+        })
+    }
+}
+
+/// fill `buf` from `file` at `pos`, leaving the cursor of the handle alone
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, pos)
+}
+
+/// fill `buf` from `file` at `pos`, leaving the cursor of the handle alone
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, pos) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) => {
+                buf = &mut buf[len..];
+                pos += len as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+struct KvStoreWriter {
+    writer: BufWriterWithPos<File>,
+    current_gen: u64,
+    /// `current_gen` as the readers see it
+    active_gen: Arc<AtomicU64>,
+    /// the file of `blob_writer` as the readers see it
+    active_blob: Arc<AtomicU64>,
+    /// size and garbage of every generation that no compaction took on yet
+    segments: BTreeMap<u64, SegmentStats>,
+    /// size of all log files together
+    log_size: u64,
+    /// whether the active log has writes the periodic syncer has not synced yet
+    unsynced: bool,
+    /// whether the compaction thread is working on a compaction
+    compacting: bool,
+    /// generations a compaction took on, live until it swaps in its outputs
+    compaction_inputs: BTreeSet<u64>,
+    /// number of compactions that finished
+    compactions: u64,
+    /// how long the latest of them took
+    last_compaction: Option<Duration>,
+    /// the blob file values are written to with its id, opened for the first value
+    blob_writer: Option<(u64, BufWriterWithPos<File>)>,
+    /// id of the next blob file
+    next_blob_file: u64,
+    /// size and garbage of every blob file
+    blobs: BTreeMap<u64, BlobStats>,
+    compaction_tx: Sender<CompactionMsg>,
+    path: Arc<PathBuf>,
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    versions: Arc<Versions>,
+    /// key new records are sealed with
+    cipher: Option<Cipher>,
+    options: Arc<KvStoreOptions>,
+}
+
+impl KvStoreWriter {
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.contains(&key) {
+            self.apply(Command::remove(key))
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    /// append a single command, make it durable and index it
+    fn apply(&mut self, cmd: Command) -> Result<()> {
+        let (cmd, seq, range) = self.write_command(cmd)?;
+        self.commit_writes()?;
+        self.index_command(cmd, seq, range);
+        self.after_write();
+        Ok(())
+    }
+
+    /// Write `cmd` to the active log with the next sequence, without flushing it.
+    ///
+    /// Returns the command as written, with its values compressed or moved to blob
+    /// files as the options ask.
+    fn write_command(&mut self, cmd: Command) -> Result<(Command, u64, Range<u64>)> {
+        let seq = self.versions.next_seq();
+        let mut cmd = self.write_blobs(cmd, seq)?.compress(&self.options)?;
+        let pos = self.writer.pos;
+        self.writer
+            .write_all(&cmd.encode(seq, self.cipher.as_ref()))?;
+        self.log_size += self.writer.pos - pos;
+        if let Some(stats) = self.segments.get_mut(&self.current_gen) {
+            stats.size += self.writer.pos - pos;
+        }
+        Ok((cmd, seq, pos..self.writer.pos))
+    }
+
+    /// write the values of sets at or above the blob threshold to the blob file,
+    /// leaving pointers to them
+    fn write_blobs(&mut self, cmd: Command, seq: u64) -> Result<Command> {
+        let threshold = match self.options.blob_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(cmd),
+        };
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                compression: None,
+                blob: None,
+            } if value.len() >= threshold => {
+                let blob = self.write_blob(Command::set(key.clone(), value), seq)?;
+                Ok(Command::set_blob(key, expires_at, blob))
+            }
+            Command::Batch(cmds) => {
+                let cmds = cmds
+                    .into_iter()
+                    .map(|(cmd, _)| self.write_blobs(cmd, seq))
+                    .collect::<Result<_>>()?;
+                Ok(Command::batch(cmds))
+            }
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// append the set record of a value to the active blob file
+    fn write_blob(&mut self, cmd: Command, seq: u64) -> Result<BlobPos> {
+        let mut cmd = cmd.compress(&self.options)?;
+        if self.blob_writer.is_none() {
+            let file = self.next_blob_file;
+            self.next_blob_file += 1;
+            let writer = open_log_writer(&blob_path(&self.path, file), &self.options)?;
+            self.blobs.insert(
+                file,
+                BlobStats {
+                    size: writer.pos,
+                    garbage: 0,
+                    key_id: self.cipher.as_ref().map(Cipher::key_id),
+                },
+            );
+            self.blob_writer = Some((file, writer));
+            self.active_blob.store(file, Ordering::SeqCst);
+        }
+        let (file, writer) = self.blob_writer.as_mut().unwrap();
+        let pos = writer.pos;
+        writer.write_all(&cmd.encode(seq, self.cipher.as_ref()))?;
+        let blob = BlobPos {
+            file: *file,
+            pos,
+            len: writer.pos - pos,
+        };
+        if let Some(stats) = self.blobs.get_mut(&blob.file) {
+            stats.size += blob.len;
+        }
+        Ok(blob)
+    }
+
+    /// make the written commands as durable as the sync policy asks
+    fn commit_writes(&mut self) -> Result<()> {
+        // values have to be as durable as the pointers to them before those are
+        if let Some((_, blob_writer)) = &mut self.blob_writer {
+            match self.options.sync_policy {
+                SyncPolicy::Always | SyncPolicy::GroupCommit => blob_writer.sync()?,
+                _ => blob_writer.flush()?,
+            }
+        }
+        match self.options.sync_policy {
+            SyncPolicy::Never => self.writer.flush()?,
+            SyncPolicy::Always | SyncPolicy::GroupCommit => self.writer.sync()?,
+            SyncPolicy::Interval(_) => {
+                self.writer.flush()?;
+                self.unsynced = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// point the index at a command that was written to `range` of the active log
+    fn index_command(&mut self, cmd: Command, seq: u64, range: Range<u64>) {
+        match cmd {
+            Command::Set {
+                key,
+                expires_at,
+                blob,
+                ..
+            } => {
+                let old_pos = self.index.get(&key).map(|entry| *entry.value());
+                self.versions.record(&key, seq, old_pos);
+                if let Some(old_pos) = old_pos {
+                    self.mark_stale(old_pos);
+                }
+                let cmd_pos = CommandPos::new(self.current_gen, range, expires_at, seq, blob);
+                self.index.insert(key, cmd_pos);
+            }
+            Command::Remove { key } => {
+                let old_pos = self.index.get(&key).map(|entry| *entry.value());
+                self.versions.record(&key, seq, old_pos);
+                if let Some(old_pos) = self.index.remove(&key).map(|entry| *entry.value()) {
+                    self.mark_stale(old_pos);
+                }
+                self.mark_segment_garbage(self.current_gen, range.end - range.start);
+            }
+            Command::Batch(cmds) => {
+                self.mark_segment_garbage(self.current_gen, batch_header_len(&cmds, &range));
+                for (cmd, inner) in cmds {
+                    let inner = range.start + inner.start..range.start + inner.end;
+                    self.index_command(cmd, seq, inner);
+                }
+            }
+        }
+    }
+
+    /// whether `key` exists and has not expired
+    fn contains(&self, key: &[u8]) -> bool {
+        self.index
+            .get(key)
+            .is_some_and(|entry| !entry.value().is_expired())
+    }
+
+    fn mark_stale(&mut self, pos: CommandPos) {
+        self.mark_segment_garbage(pos.gen, pos.len);
+        if let Some(blob) = pos.blob {
+            self.mark_blob_garbage(blob);
+        }
+    }
+
+    /// count `len` bytes of `gen` as stale, unless a compaction took the generation on
+    fn mark_segment_garbage(&mut self, gen: u64, len: u64) {
+        if let Some(stats) = self.segments.get_mut(&gen) {
+            stats.garbage += len;
+        }
+    }
+
+    fn mark_blob_garbage(&mut self, blob: BlobPos) {
+        if let Some(stats) = self.blobs.get_mut(&blob.file) {
+            stats.garbage += blob.len;
+        }
+    }
+
+    /// Roll over to a new segment or start a compaction once the log asks for it.
+    ///
+    /// The write is durable by then, so a failure is only logged and the next write
+    /// tries again.
+    fn after_write(&mut self) {
+        if let Err(e) = self.roll_full_files().and_then(|()| self.maybe_compact()) {
+            error!("Rolling over or compacting after a write failed: {}", e);
+        }
+    }
+
+    /// start new files in place of the ones that reached the segment size
+    fn roll_full_files(&mut self) -> Result<()> {
+        if let Some(max_segment_size) = self.options.max_segment_size {
+            if self.writer.pos >= max_segment_size {
+                self.roll_to(self.current_gen + 1)?;
+            }
+            if let Some((_, blob_writer)) = &mut self.blob_writer {
+                if blob_writer.pos >= max_segment_size {
+                    // the next value opens a new blob file
+                    blob_writer.sync()?;
+                    self.blob_writer = None;
+                    self.active_blob.store(0, Ordering::SeqCst);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// start a compaction if enough of the log or of a blob file is stale
+    fn maybe_compact(&mut self) -> Result<()> {
+        if !self.compacting
+            && (self
+                .options
+                .compaction_trigger
+                .should_compact(self.uncompacted(), self.log_size)
+                && self
+                    .segments
+                    .values()
+                    .any(|stats| stats.should_compact(&self.options, self.cipher.as_ref()))
+                || self.blobs.iter().any(|(&file, stats)| {
+                    !self.is_active_blob(file) && stats.should_collect(&self.options)
+                }))
+        {
+            self.start_compaction()?;
+        }
+        Ok(())
+    }
+
+    /// stale bytes that the next compaction can reclaim
+    fn uncompacted(&self) -> u64 {
+        self.segments.values().map(|stats| stats.garbage).sum()
+    }
+
+    fn is_active_blob(&self, file: u64) -> bool {
+        self.blob_writer
+            .as_ref()
+            .is_some_and(|&(active, _)| active == file)
+    }
+
+    /// make `gen` the active generation, syncing the previous one if it is behind
+    fn roll_to(&mut self, gen: u64) -> Result<()> {
+        if self.unsynced {
+            if let Some((_, blob_writer)) = &mut self.blob_writer {
+                blob_writer.sync()?;
+            }
+            self.writer.sync()?;
+            self.unsynced = false;
+        }
+        self.current_gen = gen;
+        // replacing the writer flushes the previous log before readers may map it
+        self.writer = new_log_file(&self.path, gen, &self.options)?;
+        self.active_gen.store(gen, Ordering::SeqCst);
+        self.log_size += self.writer.pos;
+        self.segments.insert(
+            gen,
+            SegmentStats::new(self.writer.pos, self.cipher.as_ref()),
+        );
+        Ok(())
+    }
+
+    /// hand out handles of the active files if they have writes that still need a
+    /// sync, in the order they have to be synced
+    fn take_unsynced(&mut self) -> Result<Vec<File>> {
+        if !self.unsynced {
+            return Ok(Vec::new());
+        }
+        self.unsynced = false;
+        let mut files = Vec::new();
+        if let Some((_, blob_writer)) = &self.blob_writer {
+            files.push(blob_writer.writer.get_ref().try_clone()?);
+        }
+        files.push(self.writer.writer.get_ref().try_clone()?);
+        Ok(files)
+    }
+
+    /// Switch to a fresh generation and let the compaction thread merge the older ones
+    /// that the options ask to compact.
+    ///
+    /// Enough generations are reserved between the old and the new active one for the
+    /// live data to fit into segments of the maximum size. Blob files with enough
+    /// garbage or sealed with an old key are collected along the way, into a blob
+    /// file of their own.
+    fn start_compaction(&mut self) -> Result<()> {
+        let key_id = self.cipher.as_ref().map(Cipher::key_id);
+        let blobs: Vec<u64> = self
+            .blobs
+            .iter()
+            .filter(|&(&file, stats)| {
+                !self.is_active_blob(file)
+                    && (stats.should_collect(&self.options) || stats.key_id != key_id)
+            })
+            .map(|(&file, _)| file)
+            .collect();
+        let blob_file = self.next_blob_file;
+        self.next_blob_file += 1;
+
+        let mut inputs = BTreeSet::new();
+        let mut live = 0;
+        for (&gen, stats) in &self.segments {
+            if stats.should_compact(&self.options, self.cipher.as_ref()) {
+                inputs.insert(gen);
+                live += stats.size.saturating_sub(stats.garbage);
+            }
+        }
+        let reserved = match self.options.max_segment_size {
+            Some(max) => live / max.max(1) + 2,
+            None => 1,
+        };
+        let outputs = self.current_gen + 1..self.current_gen + 1 + reserved;
+        self.roll_to(outputs.end)?;
+        // the compaction accounts for the records of its inputs from here on
+        self.segments.retain(|gen, _| !inputs.contains(gen));
+        self.compaction_inputs.extend(&inputs);
+        let oldest_stale = self
+            .segments
+            .iter()
+            .find(|(_, stats)| stats.garbage > 0)
+            .map(|(&gen, _)| gen);
+        self.compacting = true;
+        self.compaction_tx
+            .send(CompactionMsg::Compact(CompactionJob {
+                inputs,
+                outputs,
+                oldest_stale,
+                blobs,
+                blob_file,
+            }))
+            .map_err(|_| KvsError::StringError("the compaction thread is gone".to_owned()))
+    }
+}
+
+struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    fn with_capacity(capacity: usize, mut inner: R) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::Current(0))?;
+        Ok(BufReaderWithPos {
+            reader: BufReader::with_capacity(capacity, inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    fn with_capacity(capacity: usize, mut inner: W) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::End(0))?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::with_capacity(capacity, inner),
+            pos,
+        })
+    }
+}
+
+impl BufWriterWithPos<File> {
+    /// flush the buffer and make the written data durable
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct CommandPos {
+    gen: u64,
+    pos: u64,
+    len: u64,
+    /// unix millis after which the key is gone
+    expires_at: Option<u64>,
+    /// sequence of the write of the record
+    seq: u64,
+    /// where the value is if the record points to it
+    blob: Option<BlobPos>,
+}
+
+impl CommandPos {
+    fn new(
+        gen: u64,
+        range: Range<u64>,
+        expires_at: Option<u64>,
+        seq: u64,
+        blob: Option<BlobPos>,
+    ) -> CommandPos {
+        CommandPos {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+            expires_at,
+            seq,
+            blob,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(expiry::is_expired)
+    }
+}
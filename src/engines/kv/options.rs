@@ -0,0 +1,260 @@
+use std::fmt;
+use std::time::Duration;
+
+use super::crypto::Cipher;
+use crate::SyncPolicy;
+
+/// When the log of a `KvStore` gets compacted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionTrigger {
+    /// compact once this many bytes of the log are stale
+    StaleBytes(u64),
+    /// compact once this share of the log is stale, but not before `min_stale_bytes` are
+    StaleRatio {
+        /// share of stale bytes in the whole log, between 0 and 1
+        ratio: f64,
+        /// stale bytes below which no compaction is started
+        min_stale_bytes: u64,
+    },
+}
+
+impl CompactionTrigger {
+    pub(super) fn should_compact(&self, stale_bytes: u64, log_size: u64) -> bool {
+        match *self {
+            CompactionTrigger::StaleBytes(threshold) => stale_bytes > threshold,
+            CompactionTrigger::StaleRatio {
+                ratio,
+                min_stale_bytes,
+            } => stale_bytes > min_stale_bytes && stale_bytes as f64 > ratio * log_size as f64,
+        }
+    }
+}
+
+/// How values are compressed in the log of a `KvStore`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4, fast with a moderate ratio
+    Lz4,
+    /// Zstandard, slower with a better ratio
+    Zstd,
+}
+
+/// A key to encrypt the log of a `KvStore` with
+///
+/// The id is stored in the header of every file the key encrypts, so the right key
+/// is picked when the file is read again.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: u32,
+    key: [u8; 32],
+}
+
+impl EncryptionKey {
+    /// a 256 bit `key` known by `id`
+    pub fn new(id: u32, key: [u8; 32]) -> EncryptionKey {
+        EncryptionKey { id, key }
+    }
+
+    fn cipher(&self) -> Cipher {
+        Cipher::new(self.id, &self.key)
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the key itself must not end up in logs
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Settings for opening a `KvStore`
+///
+/// ```rust
+/// # use kvs::{CompactionTrigger, KvStoreOptions, SyncPolicy};
+/// let options = KvStoreOptions::default()
+///     .compaction_trigger(CompactionTrigger::StaleBytes(64 * 1024 * 1024))
+///     .sync_policy(SyncPolicy::Always);
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(super) compaction_trigger: CompactionTrigger,
+    pub(super) max_segment_size: Option<u64>,
+    pub(super) read_buffer_size: usize,
+    pub(super) write_buffer_size: usize,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) ttl_sweep_interval: Duration,
+    pub(super) read_only: bool,
+    pub(super) compression: Option<Compression>,
+    pub(super) compression_threshold: usize,
+    pub(super) encryption_key: Option<EncryptionKey>,
+    pub(super) decryption_keys: Vec<EncryptionKey>,
+    pub(super) blob_threshold: Option<usize>,
+    pub(super) blob_gc_ratio: f64,
+    pub(super) segment_garbage_ratio: Option<f64>,
+    pub(super) mmap_reads: bool,
+    pub(super) value_cache_size: Option<usize>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_trigger: CompactionTrigger::StaleBytes(1024 * 1024),
+            max_segment_size: Some(64 * 1024 * 1024),
+            read_buffer_size: 8 * 1024,
+            write_buffer_size: 8 * 1024,
+            sync_policy: SyncPolicy::Never,
+            ttl_sweep_interval: Duration::from_secs(10),
+            read_only: false,
+            compression: None,
+            compression_threshold: 512,
+            encryption_key: None,
+            decryption_keys: Vec::new(),
+            blob_threshold: None,
+            blob_gc_ratio: 0.5,
+            segment_garbage_ratio: None,
+            mmap_reads: false,
+            value_cache_size: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// set when the log gets compacted, defaults to 1 MiB of stale bytes
+    pub fn compaction_trigger(mut self, trigger: CompactionTrigger) -> Self {
+        self.compaction_trigger = trigger;
+        self
+    }
+
+    /// start a new log file once the active one grows past `size` bytes, defaults to 64 MiB
+    ///
+    /// Compaction splits its output by the same size.
+    pub fn max_segment_size(mut self, size: u64) -> Self {
+        self.max_segment_size = Some(size);
+        self
+    }
+
+    /// set the buffer size used to scan whole logs when loading and compacting,
+    /// defaults to 8 KiB
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// set the buffer size of the log writer, defaults to 8 KiB
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = size;
+        self
+    }
+
+    /// set how writes are made durable, defaults to `SyncPolicy::Never`
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// set how often expired keys are swept from the index, defaults to 10 seconds
+    ///
+    /// Expired keys are never returned either way, sweeping lets their space be
+    /// reclaimed by the next compaction.
+    pub fn ttl_sweep_interval(mut self, interval: Duration) -> Self {
+        self.ttl_sweep_interval = interval;
+        self
+    }
+
+    /// open the store without ever writing to its directory, other read-only opens
+    /// can share it but no writer
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// compress values written to the log with `compression`, defaults to no compression
+    ///
+    /// Logs written with other settings stay readable, compaction rewrites their
+    /// values with the current ones.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// only compress values of at least `size` bytes, defaults to 512
+    pub fn compression_threshold(mut self, size: usize) -> Self {
+        self.compression_threshold = size;
+        self
+    }
+
+    /// encrypt the log with `key`, defaults to no encryption
+    ///
+    /// Files written without encryption or with a key also passed to
+    /// `decryption_key` stay readable, compaction rewrites them with `key`.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// also read files encrypted with `key`, such as the previous key while rotating
+    pub fn decryption_key(mut self, key: EncryptionKey) -> Self {
+        self.decryption_keys.push(key);
+        self
+    }
+
+    /// keep values of at least `size` bytes in blob files, defaults to keeping every
+    /// value in the log
+    ///
+    /// Compaction only copies a small pointer to a blob, blob files are collected
+    /// on their own once enough of them is garbage.
+    pub fn blob_threshold(mut self, size: usize) -> Self {
+        self.blob_threshold = Some(size);
+        self
+    }
+
+    /// collect a blob file once this share of it is garbage, defaults to 0.5
+    pub fn blob_gc_ratio(mut self, ratio: f64) -> Self {
+        self.blob_gc_ratio = ratio;
+        self
+    }
+
+    /// only compact the segments of which more than this share is garbage, defaults
+    /// to merging every segment
+    ///
+    /// Segments left alone are neither recompressed nor rewritten with a new key,
+    /// except for segments sealed with a key other than the encryption key, which
+    /// are always compacted.
+    pub fn segment_garbage_ratio(mut self, ratio: f64) -> Self {
+        self.segment_garbage_ratio = Some(ratio);
+        self
+    }
+
+    /// serve reads of log files that are no longer written to from memory maps,
+    /// defaults to positional reads
+    ///
+    /// Suits read-heavy workloads, the active log is always read from the file.
+    pub fn mmap_reads(mut self, mmap_reads: bool) -> Self {
+        self.mmap_reads = mmap_reads;
+        self
+    }
+
+    /// cache up to `size` bytes of the values read most recently, defaults to no cache
+    ///
+    /// Hits and misses are counted in `EngineStats`.
+    pub fn value_cache_size(mut self, size: usize) -> Self {
+        self.value_cache_size = Some(size);
+        self
+    }
+
+    /// the cipher new files are encrypted with
+    pub(super) fn cipher(&self) -> Option<Cipher> {
+        self.encryption_key.as_ref().map(EncryptionKey::cipher)
+    }
+
+    /// the cipher of the key known by `key_id`
+    pub(super) fn cipher_for(&self, key_id: u32) -> Option<Cipher> {
+        self.encryption_key
+            .iter()
+            .chain(&self.decryption_keys)
+            .find(|key| key.id == key_id)
+            .map(EncryptionKey::cipher)
+    }
+}
@@ -0,0 +1,560 @@
+//! On-disk record format of the `KvStore` log.
+//!
+//! Every log file starts with a file header:
+//!
+//! ```text
+//! | magic "KVSL" (4) | format version, u32 LE (4) | flags, u32 LE (4) |
+//! | key id, u32 LE (4) | key check (16) |
+//! ```
+//!
+//! followed by any number of records:
+//!
+//! ```text
+//! | payload len, u32 LE (4) | crc32, u32 LE (4) | type (1) | flags (1) | payload |
+//! ```
+//!
+//! The checksum covers the type, the flags and the payload, so a flipped byte or a
+//! torn write is detected per record.
+//!
+//! A set record carries `| key len, u32 LE (4) | key | value |`, preceded by
+//! `| expires at, unix millis u64 LE (8) |` if its `FLAG_EXPIRES` is set. A remove
+//! record carries just the key. A batch record carries complete set and remove
+//! records back to back, so the whole batch passes or fails one checksum.
+//!
+//! Every record carries `FLAG_SEQUENCE` and starts its payload with
+//! `| sequence, u64 LE (8) |`, the records inside a batch share the sequence of the
+//! batch.
+//!
+//! A set record with `FLAG_LZ4` or `FLAG_ZSTD` stores its value compressed with
+//! that codec, the key and the rest of the record stay as they are. Whether a value
+//! is compressed is decided per record, so a log can mix both kinds.
+//!
+//! A set record with `FLAG_BLOB` has a value of its own in a blob file, and carries
+//! `| blob file, u64 LE (8) | pos, u64 LE (8) | len, u64 LE (8) |` in place of the
+//! value.
+//!
+//! In a file with `FILE_FLAG_ENCRYPTED`, set and remove records carry `FLAG_ENCRYPTED`
+//! and their payload is sealed with the key named in the file header, with the type
+//! and flags as associated data. The key check tells a wrong key apart from a
+//! corrupted record. Batch records are not sealed themselves, the records inside are.
+//!
+//! Logs of earlier releases hold JSON commands instead, see `legacy`.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use super::blob::BlobPos;
+use super::crypto::{Cipher, KEY_CHECK_LEN};
+use super::options::{Compression, KvStoreOptions};
+use crate::{KvsError, Result};
+
+const MAGIC: [u8; 4] = *b"KVSL";
+const FORMAT_VERSION: u32 = 1;
+
+/// length of the header at the start of every log file
+const FILE_HEADER_LEN: u64 = 16 + KEY_CHECK_LEN as u64;
+
+const FILE_FLAG_ENCRYPTED: u32 = 0b1;
+/// length of the header in front of every record payload
+pub(super) const RECORD_HEADER_LEN: usize = 10;
+
+const TYPE_SET: u8 = 1;
+const TYPE_REMOVE: u8 = 2;
+const TYPE_BATCH: u8 = 3;
+
+const FLAG_EXPIRES: u8 = 0b0000_0001;
+const FLAG_SEQUENCE: u8 = 0b0000_0010;
+const FLAG_LZ4: u8 = 0b0000_0100;
+const FLAG_ZSTD: u8 = 0b0000_1000;
+const FLAG_ENCRYPTED: u8 = 0b0001_0000;
+const FLAG_BLOB: u8 = 0b0010_0000;
+
+/// length of the value of a set with `FLAG_BLOB`
+const BLOB_POS_LEN: usize = 24;
+
+/// length of the sequence at the start of a payload
+const SEQUENCE_LEN: usize = 8;
+
+/// A record read back from the log
+pub(super) struct Record {
+    pub(super) cmd: Command,
+    /// sequence of the write that appended the record
+    pub(super) seq: u64,
+    /// length of the whole frame
+    pub(super) len: u64,
+}
+
+/// A command stored in the log
+#[derive(Debug)]
+pub(super) enum Command {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// unix millis after which the key is gone
+        expires_at: Option<u64>,
+        /// codec `value` is compressed with
+        compression: Option<Compression>,
+        /// where the value is kept instead, `value` is empty then
+        blob: Option<BlobPos>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    /// commands applied all at once, each with the range of its own record
+    /// relative to the start of the batch record
+    Batch(Vec<(Command, Range<u64>)>),
+}
+
+impl Command {
+    pub(super) fn set(key: Vec<u8>, value: Vec<u8>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at: None,
+            compression: None,
+            blob: None,
+        }
+    }
+
+    pub(super) fn set_with_expiry(key: Vec<u8>, value: Vec<u8>, expires_at: u64) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+            compression: None,
+            blob: None,
+        }
+    }
+
+    pub(super) fn remove(key: Vec<u8>) -> Command {
+        Command::Remove { key }
+    }
+
+    /// a set of `key` whose value is kept at `blob`
+    pub(super) fn set_blob(key: Vec<u8>, expires_at: Option<u64>, blob: BlobPos) -> Command {
+        Command::Set {
+            key,
+            value: Vec::new(),
+            expires_at,
+            compression: None,
+            blob: Some(blob),
+        }
+    }
+
+    /// bundle sets and removes into a single batch record, the ranges of the
+    /// records are only known once it is encoded
+    pub(super) fn batch(cmds: Vec<Command>) -> Command {
+        Command::Batch(cmds.into_iter().map(|cmd| (cmd, 0..0)).collect())
+    }
+
+    /// Compress the values of sets as `options` ask, where that makes them smaller.
+    ///
+    /// Values that are already compressed are left alone.
+    pub(super) fn compress(self, options: &KvStoreOptions) -> Result<Command> {
+        let codec = match options.compression {
+            Some(codec) => codec,
+            None => return Ok(self),
+        };
+        match self {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                compression: None,
+                blob: None,
+            } if value.len() >= options.compression_threshold => {
+                let compressed = match codec {
+                    Compression::Lz4 => lz4_flex::compress_prepend_size(&value),
+                    Compression::Zstd => zstd::bulk::compress(&value, 0)?,
+                };
+                let (value, compression) = if compressed.len() < value.len() {
+                    (compressed, Some(codec))
+                } else {
+                    (value, None)
+                };
+                Ok(Command::Set {
+                    key,
+                    value,
+                    expires_at,
+                    compression,
+                    blob: None,
+                })
+            }
+            Command::Batch(cmds) => {
+                let cmds = cmds
+                    .into_iter()
+                    .map(|(cmd, _)| cmd.compress(options))
+                    .collect::<Result<_>>()?;
+                Ok(Command::batch(cmds))
+            }
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// Undo the compression of the value of a set.
+    pub(super) fn decompress(self) -> std::result::Result<Command, DecodeError> {
+        match self {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                compression: Some(compression),
+                blob,
+            } => Ok(Command::Set {
+                key,
+                value: decompress_value(compression, &value)?,
+                expires_at,
+                compression: None,
+                blob,
+            }),
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// Encode the command as a complete framed record stamped with `seq`, sealed
+    /// with `cipher` if there is one.
+    ///
+    /// The records of a batch get the ranges they are encoded to.
+    pub(super) fn encode(&mut self, seq: u64, cipher: Option<&Cipher>) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(SEQUENCE_LEN);
+        payload.extend_from_slice(&seq.to_le_bytes());
+        let (record_type, flags) = match self {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                compression,
+                blob,
+            } => {
+                payload.reserve(12 + key.len() + value.len());
+                let mut flags = FLAG_SEQUENCE;
+                match compression {
+                    Some(Compression::Lz4) => flags |= FLAG_LZ4,
+                    Some(Compression::Zstd) => flags |= FLAG_ZSTD,
+                    None => {}
+                }
+                if let Some(expires_at) = expires_at {
+                    flags |= FLAG_EXPIRES;
+                    payload.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                payload.extend_from_slice(key);
+                match blob {
+                    Some(blob) => {
+                        flags |= FLAG_BLOB;
+                        payload.extend_from_slice(&blob.file.to_le_bytes());
+                        payload.extend_from_slice(&blob.pos.to_le_bytes());
+                        payload.extend_from_slice(&blob.len.to_le_bytes());
+                    }
+                    None => payload.extend_from_slice(value),
+                }
+                (TYPE_SET, flags)
+            }
+            Command::Remove { key } => {
+                payload.extend_from_slice(key);
+                (TYPE_REMOVE, FLAG_SEQUENCE)
+            }
+            Command::Batch(cmds) => {
+                for (cmd, range) in cmds {
+                    let start = (RECORD_HEADER_LEN + payload.len()) as u64;
+                    payload.extend_from_slice(&cmd.encode(seq, cipher));
+                    *range = start..(RECORD_HEADER_LEN + payload.len()) as u64;
+                }
+                return frame(TYPE_BATCH, FLAG_SEQUENCE, &payload);
+            }
+        };
+        match cipher {
+            Some(cipher) => {
+                let flags = flags | FLAG_ENCRYPTED;
+                frame(
+                    record_type,
+                    flags,
+                    &cipher.seal(&payload, &[record_type, flags]),
+                )
+            }
+            None => frame(record_type, flags, &payload),
+        }
+    }
+
+    /// decode a payload into its command and sequence
+    fn decode(
+        record_type: u8,
+        flags: u8,
+        mut payload: Vec<u8>,
+        cipher: Option<&Cipher>,
+    ) -> std::result::Result<(Command, u64), DecodeError> {
+        if flags & FLAG_SEQUENCE == 0 {
+            return Err(DecodeError::Malformed("record has no sequence"));
+        }
+        let seq = split_u64(&mut payload).ok_or(DecodeError::Malformed("record is too short"))?;
+        let cmd = match (record_type, flags & !FLAG_SEQUENCE) {
+            (TYPE_SET, set_flags)
+                if set_flags & !(FLAG_EXPIRES | FLAG_LZ4 | FLAG_ZSTD | FLAG_BLOB) == 0 =>
+            {
+                let compression = match set_flags & (FLAG_LZ4 | FLAG_ZSTD | FLAG_BLOB) {
+                    0 | FLAG_BLOB => None,
+                    FLAG_LZ4 => Some(Compression::Lz4),
+                    FLAG_ZSTD => Some(Compression::Zstd),
+                    _ => return Err(DecodeError::Malformed("conflicting value flags")),
+                };
+                let expires_at = if flags & FLAG_EXPIRES != 0 {
+                    let expires_at = split_u64(&mut payload)
+                        .ok_or(DecodeError::Malformed("set record is too short"))?;
+                    Some(expires_at)
+                } else {
+                    None
+                };
+                if payload.len() < 4 {
+                    return Err(DecodeError::Malformed("set record is too short"));
+                }
+                let key_len = u32::from_le_bytes(payload[..4].try_into().unwrap()) as usize;
+                if payload.len() < 4 + key_len {
+                    return Err(DecodeError::Malformed("key length exceeds the record"));
+                }
+                let mut value = payload.split_off(4 + key_len);
+                let key = payload.split_off(4);
+                let blob = if set_flags & FLAG_BLOB != 0 {
+                    if value.len() != BLOB_POS_LEN {
+                        return Err(DecodeError::Malformed("blob pointer has the wrong length"));
+                    }
+                    let file = split_u64(&mut value).unwrap();
+                    let pos = split_u64(&mut value).unwrap();
+                    let len = split_u64(&mut value).unwrap();
+                    Some(BlobPos { file, pos, len })
+                } else {
+                    None
+                };
+                Command::Set {
+                    key,
+                    value,
+                    expires_at,
+                    compression,
+                    blob,
+                }
+            }
+            (TYPE_REMOVE, 0) => Command::Remove { key: payload },
+            (TYPE_BATCH, 0) => {
+                let mut cmds = Vec::new();
+                let mut rest = payload.as_slice();
+                let mut pos = (RECORD_HEADER_LEN + SEQUENCE_LEN) as u64;
+                while let Some(Record { cmd, len, .. }) = read_record(&mut rest, cipher)? {
+                    if let Command::Batch(_) = cmd {
+                        return Err(DecodeError::Malformed("nested batch"));
+                    }
+                    cmds.push((cmd, pos..pos + len));
+                    pos += len;
+                }
+                Command::Batch(cmds)
+            }
+            (TYPE_SET, _) | (TYPE_REMOVE, _) | (TYPE_BATCH, _) => {
+                return Err(DecodeError::Malformed("unknown record flags"))
+            }
+            _ => return Err(DecodeError::Malformed("unknown record type")),
+        };
+        Ok((cmd, seq))
+    }
+}
+
+/// decompress a value stored with `compression`
+pub(super) fn decompress_value(
+    compression: Compression,
+    value: &[u8],
+) -> std::result::Result<Vec<u8>, DecodeError> {
+    let value = match compression {
+        Compression::Lz4 => lz4_flex::decompress_size_prepended(value).ok(),
+        Compression::Zstd => zstd::stream::decode_all(value).ok(),
+    };
+    value.ok_or(DecodeError::Malformed("value does not decompress"))
+}
+
+/// take a little endian `u64` off the front of `payload`
+fn split_u64(payload: &mut Vec<u8>) -> Option<u64> {
+    let value = u64::from_le_bytes(payload.get(..8)?.try_into().unwrap());
+    payload.drain(..8);
+    Some(value)
+}
+
+/// Reasons a record can fail to decode
+#[derive(Debug)]
+pub(super) enum DecodeError {
+    /// the log ends in the middle of a record
+    Truncated,
+    /// the stored checksum does not match the record contents
+    Checksum,
+    /// the checksum matched but the contents are not a valid command
+    Malformed(&'static str),
+    /// the underlying reader failed
+    Io(io::Error),
+}
+
+impl DecodeError {
+    /// attach the location of the bad record
+    pub(super) fn at(self, gen: u64, pos: u64) -> KvsError {
+        let reason = match self {
+            DecodeError::Truncated => "record is truncated".to_owned(),
+            DecodeError::Checksum => "checksum mismatch".to_owned(),
+            DecodeError::Malformed(reason) => reason.to_owned(),
+            DecodeError::Io(e) => return KvsError::Io(e),
+        };
+        KvsError::Corruption { gen, pos, reason }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            DecodeError::Truncated
+        } else {
+            DecodeError::Io(error)
+        }
+    }
+}
+
+fn frame(record_type: u8, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&checksum(record_type, flags, payload).to_le_bytes());
+    buf.push(record_type);
+    buf.push(flags);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn checksum(record_type: u8, flags: u8, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[record_type, flags]);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Read the next record from `reader`, opening sealed records with `cipher`.
+///
+/// Returns `Ok(None)` on a clean end of file.
+pub(super) fn read_record<R: Read>(
+    reader: &mut R,
+    cipher: Option<&Cipher>,
+) -> std::result::Result<Option<Record>, DecodeError> {
+    let mut header = [0; RECORD_HEADER_LEN];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(DecodeError::Truncated),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let payload_len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let (record_type, mut flags) = (header[8], header[9]);
+
+    // a corrupted length must not turn into a huge allocation, so only keep what is there
+    let mut payload = Vec::new();
+    reader.take(payload_len as u64).read_to_end(&mut payload)?;
+    if payload.len() < payload_len {
+        return Err(DecodeError::Truncated);
+    }
+    if checksum(record_type, flags, &payload) != crc {
+        return Err(DecodeError::Checksum);
+    }
+    match (flags & FLAG_ENCRYPTED != 0, cipher) {
+        (true, Some(cipher)) => {
+            payload = cipher
+                .open(&payload, &[record_type, flags])
+                .ok_or(DecodeError::Malformed("record does not decrypt"))?;
+            flags &= !FLAG_ENCRYPTED;
+        }
+        (true, None) => return Err(DecodeError::Malformed("sealed record in a plain log")),
+        (false, Some(_)) if record_type != TYPE_BATCH => {
+            return Err(DecodeError::Malformed("plain record in an encrypted log"))
+        }
+        (false, _) => {}
+    }
+    let (cmd, seq) = Command::decode(record_type, flags, payload, cipher)?;
+    Ok(Some(Record {
+        cmd,
+        seq,
+        len: (RECORD_HEADER_LEN + payload_len) as u64,
+    }))
+}
+
+/// Decode a single record that was read into memory as a whole.
+pub(super) fn decode_record(
+    mut buf: &[u8],
+    cipher: Option<&Cipher>,
+) -> std::result::Result<Command, DecodeError> {
+    match read_record(&mut buf, cipher)? {
+        Some(Record { cmd, .. }) if buf.is_empty() => Ok(cmd),
+        Some(_) => Err(DecodeError::Malformed("record length mismatch")),
+        None => Err(DecodeError::Truncated),
+    }
+}
+
+/// What the header of a log file says
+pub(super) struct FileHeader {
+    /// offset of the first record
+    pub(super) len: u64,
+    /// key the records are sealed with
+    pub(super) cipher: Option<Cipher>,
+}
+
+/// Write the header at the start of a new log file, whose records `cipher` seals.
+pub(super) fn write_file_header<W: Write>(writer: &mut W, cipher: Option<&Cipher>) -> Result<()> {
+    let (flags, key_id, key_check) = match cipher {
+        Some(cipher) => (FILE_FLAG_ENCRYPTED, cipher.key_id(), cipher.key_check()),
+        None => (0, 0, [0; KEY_CHECK_LEN]),
+    };
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&flags.to_le_bytes())?;
+    writer.write_all(&key_id.to_le_bytes())?;
+    writer.write_all(&key_check)?;
+    Ok(())
+}
+
+/// Whether the log file at `path` ends inside its header, as a crash right after
+/// creating the file leaves it.
+pub(super) fn ends_in_file_header(path: &Path) -> Result<bool> {
+    let mut header = Vec::new();
+    File::open(path)?
+        .take(FILE_HEADER_LEN)
+        .read_to_end(&mut header)?;
+    Ok(header.len() < FILE_HEADER_LEN as usize)
+}
+
+/// Check the header at the start of the log file of `gen` and pick its key from
+/// `options`.
+pub(super) fn read_file_header<R: Read>(
+    reader: &mut R,
+    gen: u64,
+    options: &KvStoreOptions,
+) -> Result<FileHeader> {
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    reader
+        .read_exact(&mut header)
+        .map_err(|e| DecodeError::from(e).at(gen, 0))?;
+    if header[0..4] != MAGIC {
+        return Err(DecodeError::Malformed("not a kvs log file").at(gen, 0));
+    }
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(KvsError::UnsupportedVersion(version));
+    }
+    let flags = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    let key_id = u32::from_le_bytes(header[12..16].try_into().unwrap());
+    let cipher = match flags {
+        0 => None,
+        FILE_FLAG_ENCRYPTED => match options.cipher_for(key_id) {
+            Some(cipher) if cipher.key_check()[..] == header[16..] => Some(cipher),
+            _ => return Err(KvsError::WrongKey { gen, key_id }),
+        },
+        _ => return Err(DecodeError::Malformed("unknown file flags").at(gen, 0)),
+    };
+    Ok(FileHeader {
+        len: FILE_HEADER_LEN,
+        cipher,
+    })
+}
@@ -0,0 +1,77 @@
+use std::ops::{Bound, RangeBounds};
+
+use super::snapshot::ReadView;
+use super::KvStoreReader;
+use crate::Result;
+
+/// Lazy scan over a key range of the index.
+///
+/// Instead of borrowing the index, the scan remembers the bounds that are still left
+/// and looks the next key up again on every step, so writes made meanwhile show up
+/// or disappear just like they would for `get`. A scan of a snapshot sees none of them.
+pub(super) struct KvScan {
+    view: ReadView,
+    reader: KvStoreReader,
+    front: Bound<Vec<u8>>,
+    back: Bound<Vec<u8>>,
+}
+
+impl KvScan {
+    pub(super) fn new<R: RangeBounds<Vec<u8>>>(
+        view: ReadView,
+        reader: KvStoreReader,
+        range: R,
+    ) -> KvScan {
+        KvScan {
+            view,
+            reader,
+            front: range.start_bound().cloned(),
+            back: range.end_bound().cloned(),
+        }
+    }
+
+    fn step(&mut self, forward: bool) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            let bounds = (as_slice(&self.front), as_slice(&self.back));
+            // a key being remapped may be skipped, so any key found during a
+            // remap is looked up again
+            let key = self
+                .reader
+                .remaps
+                .read(|| self.view.next_key(bounds, forward), |_| false)?;
+            if forward {
+                self.front = Bound::Excluded(key.clone());
+            } else {
+                self.back = Bound::Excluded(key.clone());
+            }
+            match self.reader.get_with(|| self.view.lookup(&key)) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                // removed since we found it
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl Iterator for KvScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step(true)
+    }
+}
+
+impl DoubleEndedIterator for KvScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.step(false)
+    }
+}
+
+fn as_slice(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
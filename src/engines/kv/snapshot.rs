@@ -0,0 +1,333 @@
+//! Point-in-time reads of a `KvStore`.
+//!
+//! Every write takes the next sequence number. A snapshot remembers the sequence of
+//! the last write it sees, and while it is open a write that replaces or removes a
+//! key first keeps the version it replaces, so the snapshot can still find it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crossbeam_skiplist::SkipMap;
+use log::error;
+
+use super::scan::KvScan;
+use super::{CommandPos, KvStoreReader};
+use crate::engines::{prefix_range, ByteScan, Scan};
+use crate::Result;
+
+/// Sequence numbers of the writes and the versions open snapshots still need
+pub(super) struct Versions {
+    /// sequence of the latest write
+    last_seq: AtomicU64,
+    /// versions replaced while a snapshot was open, by key and the sequence of the
+    /// write that replaced them, `None` for a key that did not exist
+    history: SkipMap<(Vec<u8>, u64), Option<CommandPos>>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// number of open snapshots at each sequence
+    open: BTreeMap<u64, usize>,
+    /// files a compaction replaced while snapshots were open, with the sequence at
+    /// that time, they are removed once every snapshot that old is closed
+    retired: Vec<(u64, Vec<PathBuf>)>,
+}
+
+impl Versions {
+    pub(super) fn new(last_seq: u64) -> Versions {
+        Versions {
+            last_seq: AtomicU64::new(last_seq),
+            history: SkipMap::new(),
+            state: Mutex::default(),
+        }
+    }
+
+    /// take the sequence of the next write, only called with the writer lock held
+    pub(super) fn next_seq(&self) -> u64 {
+        self.last_seq.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Open a snapshot at the latest write.
+    ///
+    /// Must be called with the writer lock held, so no write gets between picking
+    /// the sequence and registering it.
+    pub(super) fn pin(self: &Arc<Self>) -> Pin {
+        let seq = self.last_seq.load(Ordering::SeqCst);
+        *self.state.lock().unwrap().open.entry(seq).or_insert(0) += 1;
+        Pin {
+            versions: Arc::clone(self),
+            seq,
+        }
+    }
+
+    /// Keep `old` as the version of `key` that the write `seq` replaces, if an open
+    /// snapshot may need it.
+    ///
+    /// Must be called before the index changes.
+    pub(super) fn record(&self, key: &[u8], seq: u64, old: Option<CommandPos>) {
+        let state = self.state.lock().unwrap();
+        let newest = match state.open.keys().next_back() {
+            Some(&newest) => newest,
+            None => return,
+        };
+        // a write since the newest snapshot already kept what every snapshot sees
+        let kept = (key.to_vec(), newest + 1)..=(key.to_vec(), u64::MAX);
+        if self.history.range(kept).next().is_none() {
+            self.history.insert((key.to_vec(), seq), old);
+        }
+    }
+
+    /// Hand over the files a compaction replaced, returning those that no open
+    /// snapshot needs and can be removed right away.
+    ///
+    /// Must be called with the writer lock held.
+    pub(super) fn retire(&self, files: Vec<PathBuf>) -> Vec<PathBuf> {
+        let mut state = self.state.lock().unwrap();
+        if state.open.is_empty() {
+            return files;
+        }
+        let seq = self.last_seq.load(Ordering::SeqCst);
+        state.retired.push((seq, files));
+        Vec::new()
+    }
+
+    /// whether a write replaced or removed `key` after `seq`, only known while a
+    /// snapshot at `seq` is open
+    fn changed_since(&self, key: &[u8], seq: u64) -> bool {
+        let kept = (key.to_vec(), seq + 1)..=(key.to_vec(), u64::MAX);
+        self.history.range(kept).next().is_some()
+    }
+
+    /// where `key` was as of `seq`
+    fn lookup(
+        &self,
+        index: &SkipMap<Vec<u8>, CommandPos>,
+        key: &[u8],
+        seq: u64,
+    ) -> Option<CommandPos> {
+        // the index goes first, a write replacing the key meanwhile kept the
+        // version we are after before touching the index
+        let current = index.get(key).map(|entry| *entry.value());
+        let kept = (key.to_vec(), seq + 1)..=(key.to_vec(), u64::MAX);
+        match self.history.range(kept).next() {
+            Some(entry) => *entry.value(),
+            None => current,
+        }
+    }
+
+    fn release(&self, seq: u64) {
+        let removable = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(count) = state.open.get_mut(&seq) {
+                *count -= 1;
+                if *count == 0 {
+                    state.open.remove(&seq);
+                }
+            }
+            let oldest = state.open.keys().next().copied();
+            // a kept version only serves snapshots older than the write that replaced it
+            let unused: Vec<(Vec<u8>, u64)> = self
+                .history
+                .iter()
+                .filter(|entry| oldest.is_none_or(|oldest| entry.key().1 <= oldest))
+                .map(|entry| entry.key().clone())
+                .collect();
+            for key in unused {
+                self.history.remove(&key);
+            }
+            let (removable, retired) = state
+                .retired
+                .drain(..)
+                .partition(|&(retired_at, _)| oldest.is_none_or(|oldest| retired_at < oldest));
+            state.retired = retired;
+            removable
+        };
+        for file in removable.into_iter().flat_map(|(_, files)| files) {
+            if let Err(e) = fs::remove_file(&file) {
+                error!("Removing the retired {} failed: {}", file.display(), e);
+            }
+        }
+    }
+}
+
+/// Keeps the versions of a snapshot around until it is dropped
+pub(super) struct Pin {
+    versions: Arc<Versions>,
+    seq: u64,
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        self.versions.release(self.seq);
+    }
+}
+
+/// What a read sees, either the latest state or the state of a snapshot
+#[derive(Clone)]
+pub(super) struct ReadView {
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    pin: Option<Arc<Pin>>,
+}
+
+impl ReadView {
+    pub(super) fn latest(index: Arc<SkipMap<Vec<u8>, CommandPos>>) -> ReadView {
+        ReadView { index, pin: None }
+    }
+
+    /// where the value of `key` is
+    pub(super) fn lookup(&self, key: &[u8]) -> Option<CommandPos> {
+        match &self.pin {
+            Some(pin) => pin.versions.lookup(&self.index, key, pin.seq),
+            None => self.index.get(key).map(|entry| *entry.value()),
+        }
+    }
+
+    /// the first or, going backwards, the last key within `bounds` that may have a value
+    pub(super) fn next_key(
+        &self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+        forward: bool,
+    ) -> Option<Vec<u8>> {
+        let current = {
+            let mut range = self.index.range::<[u8], _>(bounds);
+            let entry = if forward {
+                range.next()
+            } else {
+                range.next_back()
+            };
+            entry.map(|entry| entry.key().clone())
+        };
+        let pin = match &self.pin {
+            Some(pin) => pin,
+            None => return current,
+        };
+        // keys removed since the snapshot only show up in the history
+        let kept_bounds = (
+            match bounds.0 {
+                Bound::Included(key) => Bound::Included((key.to_vec(), 0)),
+                Bound::Excluded(key) => Bound::Excluded((key.to_vec(), u64::MAX)),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+            match bounds.1 {
+                Bound::Included(key) => Bound::Included((key.to_vec(), u64::MAX)),
+                Bound::Excluded(key) => Bound::Excluded((key.to_vec(), 0)),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+        );
+        let kept = {
+            let mut range = pin.versions.history.range(kept_bounds);
+            let entry = if forward {
+                range.next()
+            } else {
+                range.next_back()
+            };
+            entry.map(|entry| entry.key().0.clone())
+        };
+        match (current, kept) {
+            (Some(current), Some(kept)) if forward => Some(current.min(kept)),
+            (Some(current), Some(kept)) => Some(current.max(kept)),
+            (current, kept) => current.or(kept),
+        }
+    }
+}
+
+/// A read-only view of a `KvStore` as of the moment it was taken
+///
+/// Writes made after the snapshot are invisible to it, expiry is still checked
+/// at the time of each read. Compactions keep what open snapshots need, so
+/// long-lived snapshots hold on to disk space.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set("from".to_owned(), "100".to_owned())?;
+/// let snapshot = store.snapshot()?;
+/// store.set("from".to_owned(), "90".to_owned())?;
+/// assert_eq!(snapshot.get("from".to_owned())?, Some("100".to_owned()));
+/// # Ok(())
+/// # }
+/// # try_main().unwrap();
+/// ```
+pub struct Snapshot {
+    seq: u64,
+    view: ReadView,
+    reader: KvStoreReader,
+}
+
+impl Snapshot {
+    pub(super) fn new(
+        index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+        pin: Pin,
+        reader: KvStoreReader,
+    ) -> Snapshot {
+        Snapshot {
+            seq: pin.seq,
+            view: ReadView {
+                index,
+                pin: Some(Arc::new(pin)),
+            },
+            reader,
+        }
+    }
+
+    /// sequence of the last write the snapshot sees
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// whether a write replaced or removed `key` since the snapshot was taken
+    ///
+    /// Writes are only ever checked against this with the writer lock held.
+    pub(super) fn changed_since(&self, key: &[u8]) -> bool {
+        let pin = self.view.pin.as_ref().expect("a snapshot is always pinned");
+        pin.versions.changed_since(key, pin.seq)
+    }
+
+    /// get the value of a key as of the snapshot
+    pub fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.reader.get_with(|| self.view.lookup(&key))
+    }
+
+    /// iterate over the key/value pairs with a key in `range` as of the snapshot
+    pub fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ByteScan> {
+        Ok(ByteScan::new(KvScan::new(
+            self.view.clone(),
+            self.reader.clone(),
+            range,
+        )))
+    }
+
+    /// iterate over the key/value pairs whose key starts with `prefix` as of the snapshot
+    pub fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<ByteScan> {
+        self.scan_bytes(prefix_range(prefix))
+    }
+
+    /// get the value of a string key as of the snapshot
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// iterate over the string key/value pairs with a key in `range` as of the snapshot
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Scan> {
+        let range = (
+            range.start_bound().map(|key| key.clone().into_bytes()),
+            range.end_bound().map(|key| key.clone().into_bytes()),
+        );
+        Ok(Scan(self.scan_bytes(range)?))
+    }
+
+    /// iterate over the string key/value pairs whose key starts with `prefix` as of the snapshot
+    pub fn scan_prefix(&self, prefix: String) -> Result<Scan> {
+        Ok(Scan(self.scan_prefix_bytes(prefix.into_bytes())?))
+    }
+}
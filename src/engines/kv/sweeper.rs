@@ -0,0 +1,43 @@
+use std::sync::Mutex;
+
+use crossbeam_skiplist::SkipMap;
+
+use super::{CommandPos, KvStoreWriter};
+use crate::Result;
+
+/// Drop every expired key from the index and count its record as stale.
+///
+/// The records themselves stay in the log until a compaction removes their
+/// generation, replaying them treats an expired set like a remove. Unless
+/// `compact` is set, starting that compaction is left to a later write.
+pub(super) fn sweep_expired(
+    writer: &Mutex<KvStoreWriter>,
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    compact: bool,
+) -> Result<()> {
+    // look for candidates without blocking writers
+    let expired: Vec<(Vec<u8>, CommandPos)> = index
+        .iter()
+        .filter(|entry| entry.value().is_expired())
+        .map(|entry| (entry.key().clone(), *entry.value()))
+        .collect();
+    if expired.is_empty() {
+        return Ok(());
+    }
+
+    let mut writer = writer.lock().unwrap();
+    for (key, cmd_pos) in expired {
+        // a write may have replaced the key since
+        if index
+            .get(&key)
+            .is_some_and(|entry| *entry.value() == cmd_pos)
+        {
+            index.remove(&key);
+            writer.mark_stale(cmd_pos);
+        }
+    }
+    if compact {
+        writer.maybe_compact()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, HashSet};
+
+use super::record::Command;
+use super::{KvStore, Snapshot};
+use crate::{KvsError, Result};
+
+/// An optimistic transaction over several keys of a `KvStore`
+///
+/// Reads see the store as of `KvStore::begin_transaction` plus the transaction's
+/// own writes, which are buffered until `commit`. Dropping the transaction
+/// discards them.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set("from".to_owned(), "100".to_owned())?;
+/// let mut txn = store.begin_transaction()?;
+/// let from: u32 = txn.get("from".to_owned())?.unwrap().parse().unwrap();
+/// txn.set("from".to_owned(), (from - 10).to_string());
+/// txn.set("to".to_owned(), "10".to_owned());
+/// txn.commit()?;
+/// assert_eq!(store.get("to".to_owned())?, Some("10".to_owned()));
+/// # Ok(())
+/// # }
+/// # try_main().unwrap();
+/// ```
+pub struct Transaction {
+    store: KvStore,
+    snapshot: Snapshot,
+    /// keys whose value the transaction depends on
+    reads: HashSet<Vec<u8>>,
+    /// buffered writes, `None` removes the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(super) fn new(store: KvStore, snapshot: Snapshot) -> Transaction {
+        Transaction {
+            store,
+            snapshot,
+            reads: HashSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// get the value of a key, as written by the transaction or as of its start
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = self.snapshot.get_bytes(key.clone())?;
+        self.reads.insert(key);
+        Ok(value)
+    }
+
+    /// buffer setting a key/value pair
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// buffer removing a key, a key that does not exist is left alone
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    /// get the value of a string key, as written by the transaction or as of its start
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// buffer setting a string key/value pair
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    /// buffer removing a string key, a key that does not exist is left alone
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    /// Apply the buffered writes all at once.
+    ///
+    /// Fails with `KvsError::Conflict` and applies nothing if another writer
+    /// changed any key the transaction read since it started.
+    pub fn commit(self) -> Result<()> {
+        let mut writer = self.store.writer()?;
+        if self
+            .reads
+            .iter()
+            .any(|key| self.snapshot.changed_since(key))
+        {
+            return Err(KvsError::Conflict);
+        }
+        let cmds: Vec<Command> = self
+            .writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => Command::set(key, value),
+                None => Command::remove(key),
+            })
+            .collect();
+        if cmds.is_empty() {
+            return Ok(());
+        }
+        writer.apply(Command::batch(cmds))
+    }
+}
@@ -1,18 +1,262 @@
-use crate::Result;
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{KvsError, Result};
 /// Trait for a key value store engine
+///
+/// Keys and values are arbitrary bytes, the `String` methods are wrappers for
+/// text that fail with `KvsError::Utf8` on values that are not UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// set a key/value pair, when key is replicated, the pre-value is overwritten
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// set a key/value pair that expires once `ttl` has passed
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// get the value of a key
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// remove a key, failing with `KvsError::KeyNotFound` if it does not exist
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// the lifetime a key has left, `None` if it never expires
+    ///
+    /// Fails with `KvsError::KeyNotFound` if the key does not exist.
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// apply every write of `batch` at once, a crash applies all of them or none
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// set a key to `new` only if it holds `expected`, `None` meaning the key does not exist
+    ///
+    /// A `new` of `None` removes the key. Fails with `KvsError::CompareAndSwap` holding
+    /// the current value if the key holds anything else.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()>;
+
+    /// set a key/value pair only if the key does not exist yet
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// overwrite the value of a key only if the key exists
+    fn set_if_present_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut current = self.get_bytes(key.clone())?;
+        loop {
+            if current.is_none() {
+                return Err(KvsError::CompareAndSwap { current });
+            }
+            match self.compare_and_swap_bytes(key.clone(), current, Some(value.clone())) {
+                // the key changed since it was read, retry against its new value
+                Err(KvsError::CompareAndSwap { current: changed }) => current = changed,
+                result => return result,
+            }
+        }
+    }
+
+    /// iterate over the key/value pairs with a key in `range`, in ascending key order
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ByteScan>;
+
+    /// iterate over the key/value pairs whose key starts with `prefix`, in ascending key order
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<ByteScan> {
+        self.scan_bytes(prefix_range(prefix))
+    }
+
+    /// report how big the store is and how much of it is garbage
+    fn stats(&self) -> Result<EngineStats>;
+
+    /// write a consistent copy of the store to `dest_dir` while it keeps serving,
+    /// `dest_dir` must be empty or not exist yet
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()>;
+
     /// set a key/value pair to the KvStore, when key is replicated, the pre-value is overwritten
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// set a key/value pair to the KvStore that expires once `ttl` has passed
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
 
     /// get a value from the KvStore
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
 
     /// remove a key from the KvStore
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// the lifetime a key of the KvStore has left, `None` if it never expires
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
+
+    /// set a string key to `new` only if it holds `expected`, `None` meaning the key does not exist
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<()> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// set a key/value pair to the KvStore only if the key does not exist yet
+    fn set_if_absent(&self, key: String, value: String) -> Result<()> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// overwrite the value of a key in the KvStore only if the key exists
+    fn set_if_present(&self, key: String, value: String) -> Result<()> {
+        self.set_if_present_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// iterate over the key/value pairs with a key in `range`, in ascending key order
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Scan> {
+        // UTF-8 sorts strings the same way as their bytes
+        let range = (
+            range.start_bound().map(|key| key.clone().into_bytes()),
+            range.end_bound().map(|key| key.clone().into_bytes()),
+        );
+        Ok(Scan(self.scan_bytes(range)?))
+    }
+
+    /// iterate over the key/value pairs whose key starts with `prefix`, in ascending key order
+    fn scan_prefix(&self, prefix: String) -> Result<Scan> {
+        Ok(Scan(self.scan_prefix_bytes(prefix.into_bytes())?))
+    }
+}
+
+type BytePair = (Vec<u8>, Vec<u8>);
+
+/// Iterator over the key/value pairs of a scan
+///
+/// Pairs are read as they are consumed, so `rev` and `take` give a reverse or a
+/// limited scan without reading the rest of the range.
+pub struct ByteScan {
+    inner: Box<dyn DoubleEndedIterator<Item = Result<BytePair>> + Send>,
+}
+
+impl ByteScan {
+    pub(crate) fn new<I>(inner: I) -> ByteScan
+    where
+        I: DoubleEndedIterator<Item = Result<BytePair>> + Send + 'static,
+    {
+        ByteScan {
+            inner: Box::new(inner),
+        }
+    }
+}
+
+impl Iterator for ByteScan {
+    type Item = Result<BytePair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+impl DoubleEndedIterator for ByteScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+/// A `ByteScan` over text, yielding `KvsError::Utf8` for pairs that are not UTF-8
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set("user/1".to_owned(), "alice".to_owned())?;
+/// store.set("user/2".to_owned(), "bob".to_owned())?;
+/// let last: Vec<_> = store
+///     .scan_prefix("user/".to_owned())?
+///     .rev()
+///     .take(1)
+///     .collect::<Result<_>>()?;
+/// assert_eq!(last, vec![("user/2".to_owned(), "bob".to_owned())]);
+/// # Ok(())
+/// # }
+/// # try_main().unwrap();
+/// ```
+pub struct Scan(ByteScan);
+
+impl Iterator for Scan {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(into_strings)
+    }
+}
+
+impl DoubleEndedIterator for Scan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back().map(into_strings)
+    }
+}
+
+fn into_strings(pair: Result<BytePair>) -> Result<(String, String)> {
+    let (key, value) = pair?;
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+}
+
+/// create `dir` for a checkpoint, failing if it already holds anything
+pub(crate) fn create_checkpoint_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(KvsError::StringError(format!(
+            "{} is not empty",
+            dir.display()
+        )));
+    }
+    Ok(())
+}
+
+/// the range of every key starting with `prefix`
+pub(crate) fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    // the smallest key above all keys with the prefix increments its last byte,
+    // dropping trailing bytes that cannot be incremented
+    let mut end = prefix.clone();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix), Bound::Unbounded)
 }
 
+mod batch;
+mod durability;
+mod expiry;
 mod kv;
 mod sled;
+mod stats;
 
-pub use self::kv::KvStore;
+pub(crate) use self::batch::BatchOp;
+pub use self::batch::WriteBatch;
+pub(crate) use self::durability::PeriodicTask;
+pub use self::durability::SyncPolicy;
+pub use self::kv::{
+    CompactionTrigger, Compression, EncryptionKey, KvStore, KvStoreOptions, Snapshot, Transaction,
+};
 pub use self::sled::SledKvsEngine;
+pub use self::stats::EngineStats;
@@ -1,37 +1,419 @@
-use super::KvsEngine;
+use super::{
+    create_checkpoint_dir, expiry, BatchOp, ByteScan, EngineStats, KvsEngine, PeriodicTask,
+    SyncPolicy, WriteBatch,
+};
 use crate::{KvsError, Result};
-use sled::{Db, Tree};
+use sled::transaction::{
+    abort, ConflictableTransactionResult, TransactionError, TransactionalTree,
+};
+use sled::{Batch, Db, IVec, Transactional, Tree};
+use std::collections::HashMap;
+use std::mem;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::Duration;
+
+/// name of the tree mapping every expiring key to its expiry, as big endian unix millis
+const TTL_TREE: &[u8] = b"kvs_ttl";
+
+/// number of entries a checkpoint writes to its copy at a time
+const COPY_BATCH: usize = 1024;
 
 /// sled database wrapper
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+    sync_policy: SyncPolicy,
+    /// only held for its `Drop`, set with `SyncPolicy::Interval`
+    #[allow(dead_code)]
+    syncer: Option<Arc<PeriodicTask>>,
+    /// only held for its `Drop`, set with `with_ttl_sweeper`
+    #[allow(dead_code)]
+    sweeper: Option<Arc<PeriodicTask>>,
+    writes: Arc<Writes>,
+}
 
 impl SledKvsEngine {
-    /// create a new sled database
+    /// create a new sled database, flushing it after every write
     pub fn new(db: Db) -> Self {
-        SledKvsEngine(db)
+        SledKvsEngine {
+            db,
+            sync_policy: SyncPolicy::Always,
+            syncer: None,
+            sweeper: None,
+            writes: Arc::default(),
+        }
+    }
+
+    /// create a new sled database that makes writes durable according to `sync_policy`
+    pub fn with_sync_policy(db: Db, sync_policy: SyncPolicy) -> Result<Self> {
+        let syncer = match sync_policy {
+            SyncPolicy::Interval(interval) => {
+                let db = db.clone();
+                let syncer = PeriodicTask::spawn("kvs-sync", interval, move |_| {
+                    db.flush()?;
+                    Ok(())
+                })?;
+                Some(Arc::new(syncer))
+            }
+            _ => None,
+        };
+        Ok(SledKvsEngine {
+            db,
+            sync_policy,
+            syncer,
+            sweeper: None,
+            writes: Arc::default(),
+        })
+    }
+
+    /// remove expired keys in the background every `interval`
+    ///
+    /// Expired keys are never returned either way, sweeping frees their space.
+    pub fn with_ttl_sweeper(mut self, interval: Duration) -> Result<Self> {
+        let data: Tree = (*self.db).clone();
+        let ttl = self.ttl_tree()?;
+        let writes = Arc::clone(&self.writes);
+        let sweeper = PeriodicTask::spawn("kvs-ttl-sweep", interval, move |_| {
+            sweep_expired(&writes, &data, &ttl)
+        })?;
+        self.sweeper = Some(Arc::new(sweeper));
+        Ok(self)
+    }
+
+    fn ttl_tree(&self) -> Result<Tree> {
+        Ok(self.db.open_tree(TTL_TREE)?)
+    }
+
+    /// write `value` and its expiry in one transaction
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let ttl = self.ttl_tree()?;
+        let _write = self.writes.begin(&self.db, &ttl, &[key.as_slice()])?;
+        transaction(&self.db, &ttl, |data, ttl| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            match expires_at {
+                Some(expires_at) => ttl.insert(key.as_slice(), &expires_at.to_be_bytes())?,
+                None => ttl.remove(key.as_slice())?,
+            };
+            Ok(())
+        })?;
+        self.sync()
+    }
+
+    fn sync(&self) -> Result<()> {
+        // concurrent flushes already share sled's IO buffers, so group commit
+        // needs nothing beyond flushing every write
+        if let SyncPolicy::Always | SyncPolicy::GroupCommit = self.sync_policy {
+            self.db.flush()?;
+        }
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.insert(key, value.into_bytes()).map(|_| ())?;
-        tree.flush()?;
-        Ok(())
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.insert(key, value, None)
+    }
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.insert(key, value, Some(expiry::expires_at(ttl)))
+    }
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let tree: &Tree = &self.db;
+        match tree.get(&key)? {
+            Some(value) if !is_expired(&self.ttl_tree()?, &key)? => Ok(Some(value.to_vec())),
+            _ => Ok(None),
+        }
+    }
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let ttl = self.ttl_tree()?;
+        let _write = self.writes.begin(&self.db, &ttl, &[key.as_slice()])?;
+        transaction(&self.db, &ttl, |data, ttl| {
+            let removed = data.remove(key.as_slice())?;
+            let expires_at = ttl.remove(key.as_slice())?;
+            let expired = match expires_at.map(|at| decode_expiry(&at)).transpose() {
+                Ok(expires_at) => expires_at.is_some_and(expiry::is_expired),
+                Err(e) => return abort(e),
+            };
+            if removed.is_none() || expired {
+                return abort(KvsError::KeyNotFound);
+            }
+            Ok(())
+        })?;
+        self.sync()
+    }
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let tree: &Tree = &self.db;
+        if !tree.contains_key(&key)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        match self.ttl_tree()?.get(&key)? {
+            Some(expires_at) => {
+                let expires_at = decode_expiry(&expires_at)?;
+                if expiry::is_expired(expires_at) {
+                    return Err(KvsError::KeyNotFound);
+                }
+                Ok(Some(expiry::remaining(expires_at)))
+            }
+            None => Ok(None),
+        }
     }
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let tree: &Tree = &self.0;
-        Ok(tree
-            .get(key)?
-            .map(|i_vec| i_vec.as_ref().to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
-    }
-    fn remove(&self, key: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
-        tree.flush()?;
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut data_batch = Batch::default();
+        // every write of the batch clears a previous expiry
+        let mut ttl_batch = Batch::default();
+        let mut keys = Vec::new();
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set { key, value } => {
+                    data_batch.insert(key.as_slice(), value);
+                    ttl_batch.remove(key.as_slice());
+                    keys.push(key);
+                }
+                BatchOp::Remove { key } => {
+                    data_batch.remove(key.as_slice());
+                    ttl_batch.remove(key.as_slice());
+                    keys.push(key);
+                }
+            }
+        }
+        let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+        let ttl = self.ttl_tree()?;
+        let _write = self.writes.begin(&self.db, &ttl, &keys)?;
+        transaction(&self.db, &ttl, |data, ttl| {
+            data.apply_batch(&data_batch)?;
+            ttl.apply_batch(&ttl_batch)?;
+            Ok(())
+        })?;
+        self.sync()
+    }
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        // sled's own compare_and_swap cannot clear the expiry of the key in the
+        // same step, so the check runs in a transaction over both trees instead
+        let ttl = self.ttl_tree()?;
+        let _write = self.writes.begin(&self.db, &ttl, &[key.as_slice()])?;
+        transaction(&self.db, &ttl, |data, ttl| {
+            let current = match data.get(key.as_slice())? {
+                Some(value) => match ttl.get(key.as_slice())? {
+                    Some(at) => match decode_expiry(&at) {
+                        Ok(at) if expiry::is_expired(at) => None,
+                        Ok(_) => Some(value),
+                        Err(e) => return abort(e),
+                    },
+                    None => Some(value),
+                },
+                None => None,
+            };
+            if current.as_deref() != expected.as_deref() {
+                return abort(KvsError::CompareAndSwap {
+                    current: current.map(|value| value.to_vec()),
+                });
+            }
+            match &new {
+                Some(value) => data.insert(key.as_slice(), value.as_slice())?,
+                None => data.remove(key.as_slice())?,
+            };
+            ttl.remove(key.as_slice())?;
+            Ok(())
+        })?;
+        self.sync()
+    }
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ByteScan> {
+        let tree: &Tree = &self.db;
+        let ttl = self.ttl_tree()?;
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(ByteScan::new(tree.range(range).filter_map(move |pair| {
+            let live = |(key, value): (IVec, IVec)| -> Result<_> {
+                Ok(if is_expired(&ttl, &key)? {
+                    None
+                } else {
+                    Some((key.to_vec(), value.to_vec()))
+                })
+            };
+            pair.map_err(KvsError::from).and_then(live).transpose()
+        })))
+    }
+
+    /// sled reclaims space on its own, so only the live data and the size on disk
+    /// are reported
+    fn stats(&self) -> Result<EngineStats> {
+        let ttl = self.ttl_tree()?;
+        let mut stats = EngineStats::default();
+        for pair in self.db.iter() {
+            let (key, value) = pair?;
+            if !is_expired(&ttl, &key)? {
+                stats.live_keys += 1;
+                stats.live_bytes += (key.len() + value.len()) as u64;
+            }
+        }
+        stats.disk_size = self.db.size_on_disk()?;
+        Ok(stats)
+    }
+
+    /// writes go on while the trees are copied, each key and its expiry are
+    /// copied as they were when the checkpoint started
+    fn checkpoint(&self, dest_dir: &Path) -> Result<()> {
+        create_checkpoint_dir(dest_dir)?;
+        let copy = sled::open(dest_dir)?;
+        self.writes.checkpoint(
+            [&self.db, &self.ttl_tree()?],
+            [&copy, &copy.open_tree(TTL_TREE)?],
+        )?;
+        copy.flush()?;
         Ok(())
     }
 }
+
+/// data and expiry of a key, `None` where it has none
+type Versions = (Option<IVec>, Option<IVec>);
+
+/// Lets a checkpoint copy the trees as of one point while writes go on.
+///
+/// While a checkpoint runs, every write first keeps the data and expiry its keys
+/// had when the checkpoint started, unless an earlier write kept them already. The
+/// checkpoint copies the keys no write kept and then the kept ones, so it never
+/// copies anything written after it started.
+#[derive(Default)]
+struct Writes {
+    /// held shared by every write and exclusively while a checkpoint starts
+    gate: RwLock<()>,
+    /// the versions kept for the running checkpoint, `None` when none is running
+    kept: Mutex<Option<HashMap<IVec, Versions>>>,
+    /// held by the running checkpoint
+    checkpoint: Mutex<()>,
+}
+
+impl Writes {
+    /// Keep the versions of `keys` for the running checkpoint, if there is one.
+    ///
+    /// The returned guard keeps a checkpoint from starting until the write is done.
+    fn begin(&self, data: &Tree, ttl: &Tree, keys: &[&[u8]]) -> Result<RwLockReadGuard<'_, ()>> {
+        let gate = self.gate.read().unwrap();
+        if let Some(kept) = self.kept.lock().unwrap().as_mut() {
+            for &key in keys {
+                if !kept.contains_key(key) {
+                    kept.insert(key.into(), (data.get(key)?, ttl.get(key)?));
+                }
+            }
+        }
+        Ok(gate)
+    }
+
+    /// Copy the data and the ttl tree in `src` to the ones in `dest`, as they are
+    /// now.
+    ///
+    /// Only waits for the writes in flight, and only to start.
+    fn checkpoint(&self, [data, ttl]: [&Tree; 2], [data_copy, ttl_copy]: [&Tree; 2]) -> Result<()> {
+        let _checkpoint = self.checkpoint.lock().unwrap();
+        {
+            let _gate = self.gate.write().unwrap();
+            *self.kept.lock().unwrap() = Some(HashMap::new());
+        }
+        let copied = self
+            .copy_unwritten(data, data_copy)
+            .and_then(|()| self.copy_unwritten(ttl, ttl_copy));
+        let kept = self.kept.lock().unwrap().take().unwrap_or_default();
+        copied?;
+
+        let mut kept_data = Vec::new();
+        let mut kept_ttl = Vec::new();
+        for (key, (value, expires_at)) in kept {
+            if let Some(value) = value {
+                kept_data.push(Ok((key.clone(), value)));
+            }
+            if let Some(expires_at) = expires_at {
+                kept_ttl.push(Ok((key, expires_at)));
+            }
+        }
+        apply_in_batches(data_copy, kept_data)?;
+        apply_in_batches(ttl_copy, kept_ttl)
+    }
+
+    /// copy the entries of `src` whose keys no write kept to `dest`
+    fn copy_unwritten(&self, src: &Tree, dest: &Tree) -> Result<()> {
+        // a write keeps the versions of a key before it changes it, so an entry
+        // read before its key is found unkept is as of the start
+        let entries = src.iter().filter(|entry| match entry {
+            Ok((key, _)) => !self
+                .kept
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|kept| kept.contains_key(key)),
+            Err(_) => true,
+        });
+        apply_in_batches(dest, entries.map(|entry| entry.map_err(KvsError::from)))
+    }
+}
+
+/// run `f` on the data and the ttl tree in one transaction
+fn transaction<T, F>(data: &Tree, ttl: &Tree, f: F) -> Result<T>
+where
+    F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, KvsError>,
+{
+    (data, ttl)
+        .transaction(|(data, ttl)| f(data, ttl))
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })
+}
+
+/// write `entries` to `tree` a bounded batch at a time
+fn apply_in_batches(
+    tree: &Tree,
+    entries: impl IntoIterator<Item = Result<(IVec, IVec)>>,
+) -> Result<()> {
+    let mut batch = Batch::default();
+    let mut len = 0;
+    for entry in entries {
+        let (key, value) = entry?;
+        batch.insert(key, value);
+        len += 1;
+        if len == COPY_BATCH {
+            tree.apply_batch(mem::take(&mut batch))?;
+            len = 0;
+        }
+    }
+    tree.apply_batch(batch)?;
+    Ok(())
+}
+
+fn decode_expiry(bytes: &[u8]) -> Result<u64> {
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| KvsError::StringError("malformed expiry in the ttl tree".to_owned()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn is_expired(ttl: &Tree, key: &[u8]) -> Result<bool> {
+    match ttl.get(key)? {
+        Some(expires_at) => Ok(expiry::is_expired(decode_expiry(&expires_at)?)),
+        None => Ok(false),
+    }
+}
+
+/// remove every expired key together with its expiry
+fn sweep_expired(writes: &Writes, data: &Tree, ttl: &Tree) -> Result<()> {
+    for entry in ttl.iter() {
+        let (key, expires_at) = entry?;
+        if !expiry::is_expired(decode_expiry(&expires_at)?) {
+            continue;
+        }
+        let _write = writes.begin(data, ttl, &[&key])?;
+        transaction(data, ttl, |data, ttl_tx| {
+            // the key may have been written again since
+            if ttl_tx.get(&key)?.as_ref() == Some(&expires_at) {
+                data.remove(&key)?;
+                ttl_tx.remove(&key)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Size and activity of a store, as reported by `KvsEngine::stats`
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set("key".to_owned(), "value1".to_owned())?;
+/// store.set("key".to_owned(), "value2".to_owned())?;
+/// let stats = store.stats()?;
+/// assert_eq!(stats.live_keys, 1);
+/// assert!(stats.dead_bytes > 0);
+/// # Ok(())
+/// # }
+/// # try_main().unwrap();
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EngineStats {
+    /// number of keys that exist and have not expired
+    pub live_keys: u64,
+    /// bytes on disk holding the current value of a live key
+    pub live_bytes: u64,
+    /// bytes on disk that the next compaction can reclaim
+    pub dead_bytes: u64,
+    /// the dead bytes of every log generation that has any
+    pub garbage_by_gen: BTreeMap<u64, u64>,
+    /// number of log and blob files held open for reading
+    pub open_readers: usize,
+    /// number of compactions that finished since the store was opened
+    pub compactions: u64,
+    /// how long the latest of them took
+    pub last_compaction: Option<Duration>,
+    /// size of every file of the store together
+    pub disk_size: u64,
+    /// number of reads served by the value cache
+    pub cache_hits: u64,
+    /// number of reads the value cache missed, zero without a cache
+    pub cache_misses: u64,
+}
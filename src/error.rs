@@ -27,6 +27,55 @@ pub enum KvsError {
     /// String error
     #[fail(display = "{}", _0)]
     StringError(String),
+    /// a log record failed its checksum or could not be decoded
+    #[fail(
+        display = "corrupted record in {}.log at offset {}: {}",
+        gen, pos, reason
+    )]
+    Corruption {
+        /// generation of the corrupted log file
+        gen: u64,
+        /// offset of the corrupted record
+        pos: u64,
+        /// what was wrong with the record
+        reason: String,
+    },
+    /// a write was attempted on a store opened read-only
+    #[fail(display = "the store is opened read-only")]
+    ReadOnly,
+    /// the log file was written with an unknown format version
+    #[fail(display = "unsupported log format version {}", _0)]
+    UnsupportedVersion(u32),
+    /// a log file is in the JSON format of an earlier release, which only a
+    /// writable open migrates
+    #[fail(
+        display = "{}.log is in the JSON format of an earlier release, open the store writable once to migrate it",
+        _0
+    )]
+    JsonLog(u64),
+    /// a conditional write found the key holding something other than expected
+    #[fail(display = "compare and swap failed")]
+    CompareAndSwap {
+        /// the value the key holds, `None` if it does not exist
+        current: Option<Vec<u8>>,
+    },
+    /// another writer changed a key the transaction read
+    #[fail(display = "transaction conflict")]
+    Conflict,
+    /// a log file is encrypted with a key that was not supplied or does not match
+    #[fail(
+        display = "{}.log is encrypted with key {}, which was not supplied or does not match",
+        gen, key_id
+    )]
+    WrongKey {
+        /// generation of the log file
+        gen: u64,
+        /// id of the key the file is encrypted with
+        key_id: u32,
+    },
+    /// another process, or another open in this one, holds the store directory
+    #[fail(display = "the store is locked by another open")]
+    Locked,
 }
 
 impl From<io::Error> for KvsError {
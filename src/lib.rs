@@ -2,7 +2,10 @@
 //! A simple key-value store
 
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    ByteScan, CompactionTrigger, Compression, EncryptionKey, EngineStats, KvStore, KvStoreOptions,
+    KvsEngine, Scan, SledKvsEngine, Snapshot, SyncPolicy, Transaction, WriteBatch,
+};
 pub use error::{KvsError, Result};
 pub use server::KvsServer;
 
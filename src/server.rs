@@ -1,7 +1,9 @@
 use crate::{
-    common::{GetResponse, RemoveResponse, Request, SetResponse},
+    common::{
+        BatchResponse, CasResponse, GetResponse, RemoveResponse, Request, SetResponse, TtlResponse,
+    },
     thread_pool::ThreadPool,
-    KvsEngine, Result,
+    KvsEngine, KvsError, Result,
 };
 use log::{debug, error};
 use serde_json::{value, Deserializer};
@@ -58,19 +60,52 @@ fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
         debug!("Recieve Request from {}: {:?}", peer_addr, req);
 
         match req {
-            Request::Get { key } => send_resp!(match engine.get(key) {
+            Request::Get { key } => send_resp!(match engine.get_bytes(key) {
                 Ok(value) => GetResponse::Ok(value),
                 Err(e) => GetResponse::Err(format!("{}", e)),
             }),
-            Request::Remove { key } => send_resp!(match engine.remove(key) {
+            Request::Remove { key } => send_resp!(match engine.remove_bytes(key) {
                 Ok(_) => RemoveResponse::Ok(()),
                 Err(e) => RemoveResponse::Err(format!("{}", e)),
             }),
-            Request::Set { key, value } => send_resp!(match engine.set(key, value) {
+            Request::Set { key, value } => send_resp!(match engine.set_bytes(key, value) {
                 Ok(_) => SetResponse::Ok(()),
                 Err(e) => SetResponse::Err(format!("{}", e)),
             }),
+            Request::SetWithTtl { key, value, ttl } => {
+                send_resp!(match engine.set_bytes_with_ttl(key, value, ttl) {
+                    Ok(_) => SetResponse::Ok(()),
+                    Err(e) => SetResponse::Err(format!("{}", e)),
+                })
+            }
+            Request::Ttl { key } => send_resp!(match engine.ttl_bytes(key) {
+                Ok(ttl) => TtlResponse::Ok(ttl),
+                Err(e) => TtlResponse::Err(format!("{}", e)),
+            }),
+            Request::Batch { batch } => send_resp!(match engine.write_batch(batch) {
+                Ok(_) => BatchResponse::Ok(()),
+                Err(e) => BatchResponse::Err(format!("{}", e)),
+            }),
+            Request::CompareAndSwap { key, expected, new } => {
+                send_resp!(cas_response(
+                    engine.compare_and_swap_bytes(key, expected, new)
+                ))
+            }
+            Request::SetIfAbsent { key, value } => {
+                send_resp!(cas_response(engine.set_if_absent_bytes(key, value)))
+            }
+            Request::SetIfPresent { key, value } => {
+                send_resp!(cas_response(engine.set_if_present_bytes(key, value)))
+            }
         };
     }
     Ok(())
 }
+
+fn cas_response(result: Result<()>) -> CasResponse {
+    match result {
+        Ok(_) => CasResponse::Ok(()),
+        Err(KvsError::CompareAndSwap { current }) => CasResponse::Mismatch(current),
+        Err(e) => CasResponse::Err(format!("{}", e)),
+    }
+}
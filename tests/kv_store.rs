@@ -1,4 +1,12 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CompactionTrigger, Compression, EncryptionKey, KvStore, KvStoreOptions, KvsEngine, KvsError,
+    Result, SledKvsEngine, SyncPolicy, WriteBatch,
+};
+use std::fs;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -124,3 +132,1534 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// A flipped byte inside a record should be detected by its checksum.
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&log, bytes)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corruption { gen: 1, .. })
+    ));
+    Ok(())
+}
+
+// A write torn by a crash at the end of the newest log should be dropped on open.
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let complete_len = fs::metadata(&log)?.len();
+    let mut file = fs::OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&[7, 0, 0, 0, 1, 2, 3])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), complete_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A bad record in the middle of the newest log is no torn write, opening should
+// fail instead of dropping the records after it.
+#[test]
+fn detect_corrupted_record_before_valid_ones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log)?;
+    let value2 = bytes.windows(6).position(|w| w == b"value2").unwrap();
+    bytes[value2] ^= 0xff;
+    fs::write(&log, &bytes)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corruption { gen: 1, .. })
+    ));
+    assert_eq!(fs::read(&log)?, bytes);
+    Ok(())
+}
+
+// A store written by an earlier release, with JSON logs, should be migrated on
+// the first writable open and refuse a read-only one until then.
+#[test]
+fn migrate_json_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key1"}}"#,
+    )?;
+    fs::write(temp_dir.path().join("2.log"), "")?;
+    fs::write(
+        temp_dir.path().join("3.log"),
+        r#"{"Set":{"key":"key2","value":"value3"}}{"Set":{"key":"key3","value":"value3"}}"#,
+    )?;
+    fs::write(temp_dir.path().join("4.log"), "")?;
+
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), KvStoreOptions::default().read_only(true)),
+        Err(KvsError::JsonLog(1))
+    ));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::default().read_only(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Compaction should leave a hint file behind that reopening can rely on,
+// and a damaged hint file should fall back to replaying the log.
+#[test]
+fn compaction_writes_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let hint_files = || -> Vec<_> {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("hint".as_ref()))
+            .collect()
+    };
+
+    let mut iter = 0;
+    while hint_files().is_empty() {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+    }
+    drop(store);
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter - 1)));
+        }
+        Ok(())
+    };
+    check()?;
+
+    let hint = hint_files().pop().unwrap();
+    let len = fs::metadata(&hint)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&hint)?
+        .set_len(len - 1)?;
+    check()
+}
+
+// Writers running while the background compaction merges old generations
+// should neither block on it nor lose any update.
+#[test]
+fn concurrent_writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..300 {
+                    for key_id in 0..100 {
+                        let key = format!("key{}_{}", thread_id, key_id);
+                        store.set(key.clone(), format!("{}", iter))?;
+                        assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for key_id in 0..100 {
+                let key = format!("key{}_{}", thread_id, key_id);
+                assert_eq!(store.get(key)?, Some("299".to_owned()));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// A read-only store should serve reads and refuse writes without touching the directory.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let files = || fs::read_dir(temp_dir.path()).unwrap().count();
+    let file_count = files();
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::default().read_only(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert_eq!(files(), file_count);
+    Ok(())
+}
+
+// Custom options should still store and compact data correctly.
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_trigger(CompactionTrigger::StaleRatio {
+            ratio: 0.5,
+            min_stale_bytes: 64 * 1024,
+        })
+        .max_segment_size(16 * 1024)
+        .read_buffer_size(512)
+        .write_buffer_size(512)
+        .sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..20 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+    Ok(())
+}
+
+// Neither the active log nor the output of compaction should grow far past the
+// maximum segment size.
+#[test]
+fn segments_stay_bounded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_trigger(CompactionTrigger::StaleBytes(32 * 1024))
+        .max_segment_size(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..10 {
+        for key_id in 0..500 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    drop(store);
+
+    let logs: Vec<_> = fs::read_dir(temp_dir.path())?
+        .flat_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .collect();
+    assert!(logs.len() > 1);
+    for log in logs {
+        // a segment is only rolled over after the write that passed the limit
+        assert!(log.metadata()?.len() < 4 * 1024 + 64);
+    }
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..500 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Writes under every sync policy should survive reopening, for both engines.
+#[test]
+fn sync_policies() -> Result<()> {
+    let policies = ["never", "always", "group", "10ms"];
+    for policy in policies.iter().map(|p| p.parse::<SyncPolicy>()) {
+        let policy = policy?;
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().sync_policy(policy);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        thread::sleep(Duration::from_millis(20));
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let db = SledKvsEngine::with_sync_policy(sled::open(temp_dir.path())?, policy)?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.remove("key1".to_owned())?;
+        db.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(db.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    assert!("sometimes".parse::<SyncPolicy>().is_err());
+    Ok(())
+}
+
+// Concurrent writers sharing group commits should all be acknowledged with the right outcome.
+#[test]
+fn group_commit_concurrent_writers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().sync_policy(SyncPolicy::GroupCommit);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..50 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key.clone(), format!("{}", key_id))?;
+                    if key_id % 2 == 0 {
+                        store.remove(key.clone())?;
+                        assert!(matches!(store.remove(key), Err(KvsError::KeyNotFound)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for thread_id in 0..8 {
+        for key_id in 0..50 {
+            let expected = if key_id % 2 == 0 {
+                None
+            } else {
+                Some(format!("{}", key_id))
+            };
+            let key = format!("key{}_{}", thread_id, key_id);
+            assert_eq!(store.get(key)?, expected);
+        }
+    }
+    Ok(())
+}
+
+// A write that is durable should succeed even if rolling over to the next segment
+// fails afterwards, with or without group commit.
+#[test]
+fn failed_rollover_after_write() -> Result<()> {
+    for sync_policy in [SyncPolicy::Always, SyncPolicy::GroupCommit] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        // the rollover cannot create the log of the next generation
+        fs::create_dir(temp_dir.path().join("2.log"))?;
+        let options = KvStoreOptions::default()
+            .max_segment_size(1)
+            .sync_policy(sync_policy);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        fs::remove_dir(temp_dir.path().join("2.log"))?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+fn check_scans<E: KvsEngine>(engine: E) -> Result<()> {
+    for key in &["a", "b/1", "b/2", "b/3", "b\u{10ffff}", "c"] {
+        engine.set(key.to_string(), format!("v{}", key))?;
+    }
+    engine.remove("b/2".to_owned())?;
+    let keys = |scan: kvs::Scan| -> Result<Vec<String>> {
+        scan.map(|pair| pair.map(|(key, _)| key)).collect()
+    };
+
+    assert_eq!(
+        keys(engine.scan("b".to_owned().."c".to_owned())?)?,
+        vec!["b/1", "b/3", "b\u{10ffff}"]
+    );
+    assert_eq!(keys(engine.scan(.."b/1".to_owned())?)?, vec!["a"]);
+    assert_eq!(
+        keys(engine.scan("c".to_owned().."a".to_owned())?)?,
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        keys(engine.scan_prefix("b/".to_owned())?)?,
+        vec!["b/1", "b/3"]
+    );
+    assert_eq!(
+        keys(engine.scan_prefix("b".to_owned())?)?,
+        vec!["b/1", "b/3", "b\u{10ffff}"]
+    );
+    assert_eq!(
+        engine.scan(..)?.rev().take(2).collect::<Result<Vec<_>>>()?,
+        vec![
+            ("c".to_owned(), "vc".to_owned()),
+            ("b\u{10ffff}".to_owned(), "vb\u{10ffff}".to_owned()),
+        ]
+    );
+
+    // both ends of the same scan meet in the middle
+    let mut scan = engine.scan_prefix("b/".to_owned())?;
+    assert_eq!(
+        scan.next_back().transpose()?.map(|(key, _)| key),
+        Some("b/3".to_owned())
+    );
+    assert_eq!(
+        scan.next().transpose()?.map(|(key, _)| key),
+        Some("b/1".to_owned())
+    );
+    assert!(scan.next().is_none());
+    Ok(())
+}
+
+// Range and prefix scans should return pairs in key order, from both ends.
+#[test]
+fn scan_ranges_and_prefixes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(KvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scans(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// Keys and values that are not UTF-8 should round-trip through both engines,
+// while the string API reports them as errors.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    fn check<E: KvsEngine>(engine: &E) -> Result<()> {
+        let key = vec![0xff, 0x00, 0xfe];
+        let value: Vec<u8> = (0..=255).collect();
+        engine.set_bytes(key.clone(), value.clone())?;
+        engine.set_bytes(vec![0xff], b"text".to_vec())?;
+        assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+        assert!(matches!(engine.get("\u{ff}".to_owned()), Ok(None)));
+        engine.set(
+            "key".to_owned(),
+            String::from_utf8_lossy(&value[..128]).into_owned(),
+        )?;
+        engine.set_bytes(b"key".to_vec(), value.clone())?;
+        assert!(matches!(
+            engine.get("key".to_owned()),
+            Err(KvsError::Utf8(_))
+        ));
+
+        let pairs = engine
+            .scan_prefix_bytes(vec![0xff])?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            pairs,
+            vec![(vec![0xff], b"text".to_vec()), (key.clone(), value)]
+        );
+        engine.remove_bytes(key.clone())?;
+        assert_eq!(engine.get_bytes(key)?, None);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(vec![0xff])?, Some(b"text".to_vec()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// Keys set with a TTL should vanish once it passes, on both engines.
+#[test]
+fn keys_expire_after_ttl() -> Result<()> {
+    fn check<E: KvsEngine>(engine: &E) -> Result<()> {
+        let ttl = Duration::from_millis(200);
+        let set_at = Instant::now();
+        engine.set_with_ttl("session".to_owned(), "alice".to_owned(), ttl)?;
+        engine.set_with_ttl(
+            "long".to_owned(),
+            "bob".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+        engine.set("plain".to_owned(), "carol".to_owned())?;
+        let session = engine.get("session".to_owned())?;
+        let session_ttl = engine.ttl("session".to_owned());
+        // a slow machine may have let the key expire already
+        if set_at.elapsed() < ttl {
+            assert_eq!(session, Some("alice".to_owned()));
+            assert!(session_ttl?.unwrap() <= ttl);
+        }
+        assert_eq!(engine.ttl("plain".to_owned())?, None);
+        assert!(engine.ttl("missing".to_owned()).is_err());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while engine.get("session".to_owned())?.is_some() {
+            assert!(Instant::now() < deadline, "session did not expire");
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(engine.ttl("session".to_owned()).is_err());
+        assert!(engine.remove("session".to_owned()).is_err());
+        let keys: Vec<String> = engine
+            .scan(..)?
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        assert_eq!(keys, vec!["long", "plain"]);
+
+        // a plain set clears the expiry of an earlier one
+        engine.set_with_ttl("plain".to_owned(), "dave".to_owned(), ttl)?;
+        engine.set("plain".to_owned(), "erin".to_owned())?;
+        assert_eq!(engine.ttl("plain".to_owned())?, None);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("bob".to_owned()));
+    assert!(store.ttl("long".to_owned())?.is_some());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// Sweeping expired keys should let compaction reclaim their space.
+#[test]
+fn expired_keys_are_reclaimed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_trigger(CompactionTrigger::StaleBytes(64 * 1024))
+        .ttl_sweep_interval(Duration::from_millis(20));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let value = "x".repeat(1024);
+    for key_id in 0..1000 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            value.clone(),
+            Duration::from_millis(50),
+        )?;
+    }
+
+    let log_size = || -> Result<u64> {
+        Ok(fs::read_dir(temp_dir.path())?
+            .flat_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().map(|metadata| metadata.len()))
+            .sum::<std::io::Result<u64>>()?)
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while log_size()? >= 64 * 1024 {
+        assert!(
+            Instant::now() < deadline,
+            "log is still {} bytes",
+            log_size()?
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+// Dropping a store should not start a compaction on its way out, even with
+// expired keys left for the last sweep.
+#[test]
+fn drop_with_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .compaction_trigger(CompactionTrigger::StaleBytes(1))
+        .ttl_sweep_interval(Duration::from_secs(3600));
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(50),
+        )?;
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while store.get("key99".to_owned())?.is_some() {
+        assert!(Instant::now() < deadline, "key99 did not expire");
+        thread::sleep(Duration::from_millis(20));
+    }
+    drop(store);
+
+    let logs: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .collect();
+    assert_eq!(logs, vec![temp_dir.path().join("1.log")]);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    Ok(())
+}
+
+// A batch should apply all of its writes, and a torn batch none of them.
+#[test]
+fn write_batches() -> Result<()> {
+    fn check<E: KvsEngine>(engine: &E) -> Result<()> {
+        engine.set("from".to_owned(), "100".to_owned())?;
+        engine.set_with_ttl(
+            "pending".to_owned(),
+            "10".to_owned(),
+            Duration::from_secs(60),
+        )?;
+        let mut batch = WriteBatch::new();
+        batch.set("from".to_owned(), "90".to_owned());
+        batch.set("to".to_owned(), "10".to_owned());
+        batch.remove("pending".to_owned());
+        batch.remove("missing".to_owned());
+        assert_eq!(batch.len(), 4);
+        engine.write_batch(batch)?;
+        assert_eq!(engine.get("from".to_owned())?, Some("90".to_owned()));
+        assert_eq!(engine.get("to".to_owned())?, Some("10".to_owned()));
+        assert_eq!(engine.get("pending".to_owned())?, None);
+        assert_eq!(engine.get("missing".to_owned())?, None);
+        engine.write_batch(WriteBatch::new())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("from".to_owned())?, Some("90".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("10".to_owned()));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "100".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("from".to_owned(), "90".to_owned());
+    batch.set("to".to_owned(), "10".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+    let log = temp_dir.path().join("1.log");
+    let file = fs::OpenOptions::new().write(true).open(&log)?;
+    file.set_len(fs::metadata(&log)?.len() - 4)?;
+    drop(file);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("from".to_owned())?, Some("100".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, None);
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// Conditional writes should only apply when the key holds the expected value,
+// and otherwise report what it holds.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    fn check<E: KvsEngine>(engine: E) -> Result<()> {
+        engine.set_if_absent("key".to_owned(), "1".to_owned())?;
+        match engine.set_if_absent("key".to_owned(), "2".to_owned()) {
+            Err(KvsError::CompareAndSwap { current }) => assert_eq!(current, Some(b"1".to_vec())),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            engine.set_if_present("missing".to_owned(), "1".to_owned()),
+            Err(KvsError::CompareAndSwap { current: None })
+        ));
+        engine.set_if_present("key".to_owned(), "2".to_owned())?;
+        assert!(engine
+            .compare_and_swap("key".to_owned(), Some("1".to_owned()), Some("3".to_owned()))
+            .is_err());
+        engine.compare_and_swap("key".to_owned(), Some("2".to_owned()), None)?;
+        assert_eq!(engine.get("key".to_owned())?, None);
+
+        // an expired key counts as absent
+        engine.set_with_ttl(
+            "session".to_owned(),
+            "a".to_owned(),
+            Duration::from_millis(50),
+        )?;
+        thread::sleep(Duration::from_millis(100));
+        engine.set_if_absent("session".to_owned(), "b".to_owned())?;
+        assert_eq!(engine.ttl("session".to_owned())?, None);
+
+        engine.set("counter".to_owned(), "0".to_owned())?;
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let engine = engine.clone();
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..25 {
+                        loop {
+                            let current = engine.get("counter".to_owned())?.unwrap();
+                            let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                            match engine.compare_and_swap(
+                                "counter".to_owned(),
+                                Some(current),
+                                Some(next),
+                            ) {
+                                Err(KvsError::CompareAndSwap { .. }) => continue,
+                                result => break result?,
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(engine.get("counter".to_owned())?, Some("100".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// A snapshot should keep seeing the store as it was when taken, across later
+// writes, removes, batches and compactions.
+#[test]
+fn snapshots_see_a_fixed_point() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["a", "b", "c"] {
+        store.set(key.to_string(), "1".to_owned())?;
+    }
+    let snapshot = store.snapshot()?;
+    store.set("a".to_owned(), "2".to_owned())?;
+    store.remove("b".to_owned())?;
+    store.set("d".to_owned(), "2".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("c".to_owned(), "2".to_owned());
+    batch.set("c".to_owned(), "3".to_owned());
+    store.write_batch(batch)?;
+
+    let check = || -> Result<()> {
+        assert_eq!(snapshot.get("a".to_owned())?, Some("1".to_owned()));
+        assert_eq!(snapshot.get("b".to_owned())?, Some("1".to_owned()));
+        assert_eq!(snapshot.get("c".to_owned())?, Some("1".to_owned()));
+        assert_eq!(snapshot.get("d".to_owned())?, None);
+        let pairs: Vec<_> = snapshot.scan(..)?.rev().collect::<Result<_>>()?;
+        let expected: Vec<_> = ["c", "b", "a"]
+            .iter()
+            .map(|key| (key.to_string(), "1".to_owned()))
+            .collect();
+        assert_eq!(pairs, expected);
+        Ok(())
+    };
+    check()?;
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, Some("3".to_owned()));
+    let later = store.snapshot()?;
+    assert!(later.seq() > snapshot.seq());
+    assert_eq!(later.get("a".to_owned())?, Some("2".to_owned()));
+
+    let log_count = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    let mut iter = 0;
+    while !temp_dir.path().join("2.hint").exists() {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+    }
+    // give the compaction time to finish and retire the old generation
+    thread::sleep(Duration::from_millis(200));
+    check()?;
+    let logs_with_snapshots = log_count();
+    drop(snapshot);
+    drop(later);
+    assert!(log_count() < logs_with_snapshots);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("c".to_owned())?, Some("3".to_owned()));
+    let reopened = store.snapshot()?;
+    store.set("a".to_owned(), "3".to_owned())?;
+    assert_eq!(reopened.get("a".to_owned())?, Some("2".to_owned()));
+    Ok(())
+}
+
+// A transaction should commit only if nobody changed what it read, including
+// keys that were missing when it read them.
+#[test]
+fn transactions_detect_conflicts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+
+    let mut txn = store.begin_transaction()?;
+    assert_eq!(txn.get("a".to_owned())?, Some("1".to_owned()));
+    txn.set("a".to_owned(), "2".to_owned());
+    txn.remove("b".to_owned());
+    assert_eq!(txn.get("a".to_owned())?, Some("2".to_owned()));
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    txn.commit()?;
+    assert_eq!(store.get("a".to_owned())?, Some("2".to_owned()));
+
+    let mut txn = store.begin_transaction()?;
+    txn.get("a".to_owned())?;
+    txn.set("c".to_owned(), "1".to_owned());
+    store.set("a".to_owned(), "3".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict)));
+    assert_eq!(store.get("c".to_owned())?, None);
+
+    // a key that is set and removed again still counts as touched
+    let mut txn = store.begin_transaction()?;
+    assert_eq!(txn.get("b".to_owned())?, None);
+    txn.set("b".to_owned(), "1".to_owned());
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.remove("b".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict)));
+
+    // writes to keys it never read do not conflict
+    let mut txn = store.begin_transaction()?;
+    txn.set("a".to_owned(), "4".to_owned());
+    store.set("a".to_owned(), "5".to_owned())?;
+    txn.commit()?;
+    assert_eq!(store.get("a".to_owned())?, Some("4".to_owned()));
+
+    store.set("x".to_owned(), "0".to_owned())?;
+    store.set("y".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    loop {
+                        let mut txn = store.begin_transaction()?;
+                        for key in &["x", "y"] {
+                            let value: u32 = txn.get(key.to_string())?.unwrap().parse().unwrap();
+                            txn.set(key.to_string(), (value + 1).to_string());
+                        }
+                        match txn.commit() {
+                            Err(KvsError::Conflict) => continue,
+                            result => break result?,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("x".to_owned())?, Some("100".to_owned()));
+    assert_eq!(store.get("y".to_owned())?, Some("100".to_owned()));
+    Ok(())
+}
+
+// Logs mixing uncompressed and differently compressed values should read back,
+// and compaction should rewrite them the way the options ask.
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || -> Result<u64> {
+        Ok(fs::read_dir(temp_dir.path())?
+            .flat_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().map(|metadata| metadata.len()))
+            .sum::<std::io::Result<u64>>()?)
+    };
+    let value = |key_id: u32| format!("{}", key_id).repeat(1000);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value(key_id))?;
+    }
+    drop(store);
+    let uncompressed_size = log_size()?;
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default().compression(Compression::Lz4),
+    )?;
+    for key_id in 100..200 {
+        store.set(format!("key{}", key_id), value(key_id))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    drop(store);
+    assert!(log_size()? < uncompressed_size * 3 / 2);
+
+    let options = KvStoreOptions::default()
+        .compression(Compression::Zstd)
+        .compaction_trigger(CompactionTrigger::StaleBytes(0));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+    }
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    // any stale record starts a compaction, dropping the store waits for it
+    store.set("small".to_owned(), "other".to_owned())?;
+    drop(store);
+    assert!(log_size()? < uncompressed_size / 10);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+    }
+    assert_eq!(store.get("small".to_owned())?, Some("other".to_owned()));
+    Ok(())
+}
+
+// Nothing written with an encryption key should be readable without it, and
+// compaction should move everything over to a new key.
+#[test]
+fn encrypted_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old_key = EncryptionKey::new(1, [1; 32]);
+    let new_key = EncryptionKey::new(2, [2; 32]);
+    let contains_plaintext = || -> Result<bool> {
+        for entry in fs::read_dir(temp_dir.path())? {
+            let contents = fs::read(entry?.path())?;
+            if contents.windows(6).any(|window| window == b"secret") {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    };
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default().encryption_key(old_key.clone()),
+    )?;
+    for key_id in 0..100 {
+        store.set(format!("secret{}", key_id), format!("secret{}", key_id))?;
+    }
+    drop(store);
+    assert!(!contains_plaintext()?);
+
+    for options in [
+        KvStoreOptions::default(),
+        KvStoreOptions::default().encryption_key(EncryptionKey::new(1, [9; 32])),
+    ] {
+        match KvStore::open_with(temp_dir.path(), options) {
+            Err(KvsError::WrongKey { key_id: 1, .. }) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("opened with the wrong key"),
+        }
+    }
+
+    let options = KvStoreOptions::default()
+        .encryption_key(new_key.clone())
+        .decryption_key(old_key)
+        .compaction_trigger(CompactionTrigger::StaleBytes(0));
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    // any stale record starts a compaction, dropping the store waits for it
+    store.set("secret0".to_owned(), "secret0".to_owned())?;
+    drop(store);
+    assert!(!contains_plaintext()?);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default().encryption_key(new_key),
+    )?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("secret{}", key_id))?,
+            Some(format!("secret{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Large values should live in blob files that compaction collects once enough of
+// them is garbage, moving the live values out.
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blob_files = || -> Result<Vec<(String, u64)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(temp_dir.path())? {
+            let entry = entry?;
+            if entry.path().extension() == Some("blob".as_ref()) {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                files.push((name, entry.metadata()?.len()));
+            }
+        }
+        files.sort();
+        Ok(files)
+    };
+    let value = |key_id: u32| format!("{}", key_id).repeat(2000);
+    let options = || {
+        KvStoreOptions::default()
+            .blob_threshold(1024)
+            .blob_gc_ratio(0.4)
+            .max_segment_size(100_000)
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value(key_id))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    drop(store);
+    let old_files = blob_files()?;
+    assert!(old_files.len() > 1);
+    let old_size: u64 = old_files.iter().map(|(_, size)| size).sum();
+
+    // every other large value becomes garbage
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+    }
+    for key_id in (0..100).step_by(2) {
+        store.set(format!("key{}", key_id), "small".to_owned())?;
+    }
+    drop(store);
+
+    // the reopened store counts the garbage and collects the files with its first write
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    store.set("small".to_owned(), "other".to_owned())?;
+    drop(store);
+    let new_files = blob_files()?;
+    assert!(new_files.iter().all(|file| !old_files.contains(file)));
+    let new_size: u64 = new_files.iter().map(|(_, size)| size).sum();
+    assert!(new_size < old_size * 2 / 3);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let expected = if key_id % 2 == 0 {
+            "small".to_owned()
+        } else {
+            value(key_id)
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(expected));
+    }
+    assert_eq!(store.get("small".to_owned())?, Some("other".to_owned()));
+    Ok(())
+}
+
+// Stats should count live keys and the garbage overwrites leave behind, and
+// report finished compactions.
+#[test]
+fn engine_stats() -> Result<()> {
+    fn check<E: KvsEngine>(engine: &E) -> Result<()> {
+        for key_id in 0..10 {
+            engine.set(format!("key{}", key_id), "value".to_owned())?;
+        }
+        engine.set("key0".to_owned(), "other".to_owned())?;
+        engine.remove("key1".to_owned())?;
+        let stats = engine.stats()?;
+        assert_eq!(stats.live_keys, 9);
+        assert!(stats.live_bytes > 0);
+        assert!(stats.disk_size > 0);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&SledKvsEngine::new(sled::open(temp_dir.path())?))?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    let stats = store.stats()?;
+    assert!(stats.dead_bytes > 0);
+    assert_eq!(stats.garbage_by_gen.values().sum::<u64>(), stats.dead_bytes);
+    assert!(stats.disk_size >= stats.live_bytes + stats.dead_bytes);
+    assert_eq!(stats.compactions, 0);
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default().compaction_trigger(CompactionTrigger::StaleBytes(0)),
+    )?;
+    store.set("key0".to_owned(), "value".to_owned())?;
+    for _ in 0..100 {
+        if store.stats()?.compactions > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.last_compaction.is_some());
+    assert_eq!(stats.live_keys, 9);
+    Ok(())
+}
+
+// Only segments that are mostly garbage should be compacted, without bringing back
+// keys whose older records stay behind in the segments left alone.
+#[test]
+fn selective_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |trigger| {
+        KvStoreOptions::default()
+            .max_segment_size(10_000)
+            .segment_garbage_ratio(0.5)
+            .compaction_trigger(trigger)
+    };
+    let value = |key_id: u32| format!("{:0100}", key_id);
+
+    // the first segments hold mostly live keys, the later ones are mostly garbage
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        options(CompactionTrigger::StaleBytes(u64::MAX)),
+    )?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    for key_id in 0..150 {
+        store.set(format!("key{}", key_id), value(key_id))?;
+    }
+    for round in 0..300 {
+        if round == 150 {
+            store.remove("removed".to_owned())?;
+        }
+        store.set("churn".to_owned(), value(round))?;
+    }
+    drop(store);
+    let first_log = temp_dir.path().join("1.log");
+    let first_contents = fs::read(&first_log)?;
+
+    let store = KvStore::open_with(temp_dir.path(), options(CompactionTrigger::StaleBytes(0)))?;
+    let log_count = || -> Result<usize> {
+        Ok(fs::read_dir(temp_dir.path())?
+            .flat_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count())
+    };
+    let logs_before = log_count()?;
+    store.set("trigger".to_owned(), "value".to_owned())?;
+    drop(store);
+    assert_eq!(fs::read(&first_log)?, first_contents);
+    assert!(log_count()? < logs_before);
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("removed".to_owned())?, None);
+        assert_eq!(store.get("churn".to_owned())?, Some(value(299)));
+        for key_id in 0..150 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+        }
+        Ok(())
+    };
+    check()?;
+    // the kept remove has to work when the log is replayed as well
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("hint".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    check()
+}
+
+// Threads should read through one shared store, with one handle per log file.
+#[test]
+fn shared_reads_across_threads() -> Result<()> {
+    fn assert_sync<T: Sync>(_: &T) {}
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default().max_segment_size(1000),
+    )?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_sync(&store);
+
+    thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| {
+                for key_id in 0..100 {
+                    assert_eq!(
+                        store.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            });
+        }
+    });
+
+    let logs = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count();
+    assert!(logs > 1);
+    assert!(store.stats()?.open_readers <= logs);
+    Ok(())
+}
+
+// Reads served from mapped logs should survive compactions removing those logs.
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .max_segment_size(1000)
+        .mmap_reads(true);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        options.compaction_trigger(CompactionTrigger::StaleBytes(0)),
+    )?;
+    // the keys read are never overwritten, other keys are to drive compactions
+    thread::scope(|scope| -> Result<()> {
+        scope.spawn(|| {
+            for _ in 0..20 {
+                for key_id in 0..100 {
+                    assert_eq!(
+                        store.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            }
+        });
+        for iter in 0..10 {
+            for key_id in 0..20 {
+                store.set(format!("churn{}", key_id), format!("{}", iter))?;
+            }
+        }
+        Ok(())
+    })?;
+    for _ in 0..100 {
+        if store.stats()?.compactions > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(store.stats()?.compactions > 0);
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Cached values should follow writes and compactions, and count hits and misses.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default()
+            .value_cache_size(1024)
+            .compaction_trigger(CompactionTrigger::StaleBytes(0)),
+    )?;
+    store.set("key".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("value1".to_owned()));
+    let stats = store.stats()?;
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+
+    let snapshot = store.snapshot()?;
+    store.set("key".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key".to_owned())?, Some("value1".to_owned()));
+    drop(snapshot);
+    store.remove("key".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    // values larger than the whole cache are never kept
+    let large = "x".repeat(2048);
+    store.set("large".to_owned(), large.clone())?;
+    store.get("large".to_owned())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+
+    for _ in 0..100 {
+        if store.stats()?.compactions > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(store.stats()?.compactions > 0);
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    assert_eq!(store.get("key".to_owned())?, None);
+    let stats = store.stats()?;
+    assert_eq!(stats.cache_hits, 2);
+    Ok(())
+}
+
+// A store directory should only be opened by one writer, or by any number of readers.
+#[test]
+fn exclusive_directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_only = || KvStoreOptions::default().read_only(true);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked)
+    ));
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), read_only()),
+        Err(KvsError::Locked)
+    ));
+    drop(store);
+
+    let reader1 = KvStore::open_with(temp_dir.path(), read_only())?;
+    let reader2 = KvStore::open_with(temp_dir.path(), read_only())?;
+    assert_eq!(reader2.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked)
+    ));
+    drop(reader1);
+    drop(reader2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A checkpoint should hold every write made before it, even with writes and
+// compactions going on meanwhile.
+#[test]
+fn checkpoints() -> Result<()> {
+    fn check<E: KvsEngine + Sync>(engine: &E, dest_dir: &std::path::Path) -> Result<()> {
+        for key_id in 0..100 {
+            engine.set(format!("key{}", key_id), "x".repeat(key_id * 20))?;
+        }
+        engine.remove("key0".to_owned())?;
+        thread::scope(|scope| -> Result<()> {
+            scope.spawn(|| {
+                for key_id in 100..300 {
+                    engine
+                        .set(format!("key{}", key_id), "value".to_owned())
+                        .unwrap();
+                }
+            });
+            engine.checkpoint(dest_dir)
+        })?;
+        assert!(engine.checkpoint(dest_dir).is_err());
+        Ok(())
+    }
+
+    let check_copy = |copy: &dyn Fn(String) -> Result<Option<String>>| -> Result<()> {
+        assert_eq!(copy("key0".to_owned())?, None);
+        for key_id in 1..100 {
+            assert_eq!(
+                copy(format!("key{}", key_id))?,
+                Some("x".repeat(key_id * 20))
+            );
+        }
+        Ok(())
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default()
+            .max_segment_size(2000)
+            .blob_threshold(1000)
+            .compaction_trigger(CompactionTrigger::StaleBytes(0)),
+    )?;
+    check(&store, dest_dir.path())?;
+    let copy = KvStore::open(dest_dir.path())?;
+    check_copy(&|key| copy.get(key))?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(
+        &SledKvsEngine::new(sled::open(temp_dir.path())?),
+        dest_dir.path(),
+    )?;
+    let copy = SledKvsEngine::new(sled::open(dest_dir.path())?);
+    check_copy(&|key| copy.get(key))?;
+    Ok(())
+}
+
+// A sled checkpoint taken while keys lose their ttl should copy every value
+// together with its own expiry.
+#[test]
+fn sled_checkpoint_keeps_expiries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    let ttl = Duration::from_secs(3600);
+    for key_id in 0..500 {
+        engine.set_with_ttl(format!("key{}", key_id), "expiring".to_owned(), ttl)?;
+    }
+    thread::scope(|scope| -> Result<()> {
+        scope.spawn(|| {
+            for key_id in 0..500 {
+                engine
+                    .set(format!("key{}", key_id), "plain".to_owned())
+                    .unwrap();
+            }
+        });
+        engine.checkpoint(dest_dir.path())
+    })?;
+
+    let copy = SledKvsEngine::new(sled::open(dest_dir.path())?);
+    for key_id in 0..500 {
+        let key = format!("key{}", key_id);
+        let expiring = copy.get(key.clone())? == Some("expiring".to_owned());
+        assert_eq!(copy.ttl(key)?.is_some(), expiring);
+    }
+    Ok(())
+}
+
+// Reading a log a compaction removed should not keep a handle to it once the
+// snapshot that needed it is gone.
+#[test]
+fn retired_logs_are_closed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default().compaction_trigger(CompactionTrigger::StaleBytes(0)),
+    )?;
+    store.set("key".to_owned(), "value1".to_owned())?;
+    let snapshot = store.snapshot()?;
+    store.set("key".to_owned(), "value2".to_owned())?;
+    for _ in 0..100 {
+        if store.stats()?.compactions > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(store.stats()?.compactions, 1);
+    assert_eq!(snapshot.get("key".to_owned())?, Some("value1".to_owned()));
+    drop(snapshot);
+
+    // only the output of the compaction is left to read from
+    assert_eq!(store.get("key".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats()?.open_readers, 1);
+    Ok(())
+}
+
+// Blob values should be read through the same shared handles and mappings as logs.
+#[test]
+fn blob_reads_share_handles() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default()
+        .blob_threshold(100)
+        .mmap_reads(true);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), "x".repeat(200 + key_id))?;
+    }
+    for _ in 0..2 {
+        for key_id in 0..3 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("x".repeat(200 + key_id))
+            );
+        }
+    }
+    // the log only holds pointers, the values come from the one blob file
+    assert_eq!(store.stats()?.open_readers, 1);
+    drop(store);
+
+    // the blob file is sealed now, so it is mapped
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..3 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("x".repeat(200 + key_id))
+        );
+    }
+    Ok(())
+}
+
+// Remapping more keys than fit in one hold of the writer lock should never hide a
+// key from readers, nor lose writes made between the chunks.
+#[test]
+fn remap_in_chunks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::default().compaction_trigger(CompactionTrigger::StaleBytes(20_000)),
+    )?;
+    for key_id in 0..3000 {
+        store.set(format!("stable{}", key_id), format!("value{}", key_id))?;
+    }
+
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| -> Result<()> {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                while !done.load(Ordering::SeqCst) {
+                    for key_id in 0..3000 {
+                        assert_eq!(
+                            store.get(format!("stable{}", key_id)).unwrap(),
+                            Some(format!("value{}", key_id))
+                        );
+                    }
+                    assert_eq!(
+                        store.scan_prefix("stable".to_owned()).unwrap().count(),
+                        3000
+                    );
+                }
+            });
+        }
+        let result = (|| -> Result<()> {
+            for iter in 0..100 {
+                for key_id in 0..100 {
+                    store.set(format!("churn{}", key_id), format!("{}", iter))?;
+                }
+            }
+            Ok(())
+        })();
+        done.store(true, Ordering::SeqCst);
+        result
+    })?;
+
+    assert!(store.stats()?.compactions > 0);
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("churn{}", key_id))?,
+            Some("99".to_owned())
+        );
+    }
+    Ok(())
+}